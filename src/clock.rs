@@ -1,25 +1,55 @@
-use rusty_link::{AblLink, SessionState};
-use std::sync::{Arc, Mutex};
+use rusty_link::AblLink;
+use arc_swap::ArcSwap;
+use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::thread::sleep;
 use std::sync::mpsc::{Receiver, Sender};
-use crate::midi::MidiConnexion;
+use crate::dispatcher::DispatcherMessage;
+use crate::midi::MidiMessage;
+use crate::midi::clock_input::ClockInput;
+use mlua::prelude::*;
 use crate::streams;
-use midir::MidiOutputConnection;
+use crate::streams::{Time, to_time};
+use crate::scheduler::Scheduler;
+use num::Zero;
+use crate::timers::{Fired, Period, Timers, Wait};
+use crate::time_source::{InternalSource, LinkSource, SourceKind, TimeSource};
+use crate::tempo::{Automation, TempoMap, TempoPoint};
 
-#[derive(Debug)]
-#[derive(Clone)]
+/// What the clock knows of its timeline, published every tick so that
+/// other threads can read it without asking. The beat is anchored to a host
+/// time, from which the current beat is extrapolated.
+#[derive(Debug, Clone, Default)]
 pub struct ClockState {
-  pub enabled: String,
-  pub num_peers: u64,
-  pub start_stop: String,
-  pub playing: String,
   pub tempo: f64,
-  pub beats: f64,
-  pub phase: f64,
-  pub metro: String,
+  /// The beat at `micros`.
+  pub beat: f64,
+  /// Host time of the anchor, in microseconds.
+  pub micros: i64,
+  pub quantum: f64,
+  pub playing: bool,
+  pub peers: u64,
+  /// The name of the time source followed.
+  pub source: &'static str,
+}
+
+impl ClockState {
+  /// The beat at host time `micros`, as long as the tempo did not change.
+  pub fn beat_at(&self, micros: i64) -> f64 {
+    self.beat + (micros - self.micros) as f64 / 60_000_000.0 * self.tempo
+  }
+
+  pub fn phase_at(&self, micros: i64) -> f64 {
+    self.beat_at(micros).rem_euclid(self.quantum)
+  }
 }
 
+/// How often the clock ticks, in milliseconds.
+pub const TICK: u64 = 20;
+
+/// The last state published by the clock thread.
+pub type SharedClockState = Arc<ArcSwap<ClockState>>;
+
 /// Return the current unix time as a std::time::Duration
 pub fn current_unix_time() -> Duration {
   let current_unix_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
@@ -27,122 +57,321 @@ pub fn current_unix_time() -> Duration {
 }
 
 pub struct Clock {
-  pub link: AblLink,
-  pub session_state: SessionState,
+  pub link: Arc<AblLink>,
+  /// The timeline followed, Link or our own.
+  source: Box<dyn TimeSource>,
   pub running: bool,
   pub quantum: f64,
-  pub snapshot: Option<ClockState>,
+  state: SharedClockState,
   pub sync: bool,
-  pub midi: Arc<Mutex<MidiConnexion>>,
-  receiver: Receiver<ClockControlMessage>,
-  sender: Sender<ClockControlMessage>,
-  subscribers: Vec<streams::Stream>
+  receiver: Receiver<ClockCommand>,
+  subscribers: Vec<streams::Stream>,
+  scheduler: Scheduler,
+  /// Incoming MIDI clock, followed when there are no Link peers.
+  clock_input: Option<ClockInput>,
+  timers: Timers,
+  /// Tempo ramp or lane being followed.
+  automation: Option<Automation>
 }
-#[derive(Debug)]
-pub struct ClockControlMessage {
+/// When a change to a stream takes effect. Boundaries are those of the Link
+/// timeline, so edits land in sync with every peer.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Quantize {
+    /// As soon as possible, after what was already scheduled.
+    Now,
+    Beat,
+    #[default]
+    Bar,
+    Bars(u32),
+}
+
+impl Quantize {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "now" => Some(Quantize::Now),
+            "beat" => Some(Quantize::Beat),
+            "bar" => Some(Quantize::Bar),
+            _ => None,
+        }
+    }
+
+    /// A whole number of bars, at least one.
+    pub fn bars(bars: f64) -> Result<Self, String> {
+        if bars.fract() != 0.0 || !(1.0..=u32::MAX as f64).contains(&bars) {
+            return Err(format!("invalid number of bars: {}", bars));
+        }
+        Ok(Quantize::Bars(bars as u32))
+    }
+}
+
+impl<'lua> FromLua<'lua> for Quantize {
+    /// `nil` (next bar), `"now"`, `"beat"`, `"bar"` or a number of bars.
+    fn from_lua(value: LuaValue<'lua>, _lua: &'lua Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Nil => Ok(Quantize::Bar),
+            LuaValue::Integer(bars) => Quantize::bars(bars as f64).map_err(LuaError::RuntimeError),
+            LuaValue::Number(bars) => Quantize::bars(bars).map_err(LuaError::RuntimeError),
+            LuaValue::String(name) => {
+                let name = name.to_str()?;
+                Quantize::from_name(name)
+                    .ok_or_else(|| LuaError::RuntimeError(format!("unknown quantize value: {}", name)))
+            },
+            other => Err(LuaError::FromLuaConversionError {
+                from: other.type_name(),
+                to: "Quantize",
+                message: Some("expected \"now\", \"beat\", \"bar\" or a number of bars".to_string()),
+            }),
+        }
+    }
+}
+
+/// What the clock needs to know to create or update a stream.
+#[derive(Debug, Clone)]
+pub struct StreamSpec {
     pub name: String,
-    pub args: Vec<String>
+    pub content: streams::StreamContent,
+    pub quantize: Quantize
+}
+
+impl StreamSpec {
+    pub fn into_stream(self, quantum: f64) -> streams::Stream {
+        let mut stream = streams::Stream::new(self.name);
+        stream.set_content(self.content, quantum);
+        stream
+    }
+}
+
+/// Commands understood by the clock thread. Queries carry the channel on
+/// which their reply must be sent, so that concurrent callers never receive
+/// each other's answers.
+#[derive(Debug)]
+pub enum ClockCommand {
+    Test,
+    Report,
+    Sync,
+    Play,
+    SetTempo(f64),
+    /// Follow a tempo lane from the given boundary, starting from the
+    /// current tempo.
+    Automate(Vec<TempoPoint>, Quantize),
+    /// Follow Link or the internal clock from now on.
+    SetTimeSource(SourceKind),
+    /// Create a stream, replacing any stream with the same name.
+    AddStream(StreamSpec),
+    /// Layer content on top of a stream, creating it if needed.
+    UpdateStream(StreamSpec),
+    RemoveStream(String, Quantize),
+    /// Send MIDI clock, Start/Stop and Song Position Pointer.
+    SetMidiClock(bool),
+    /// Follow the tempo and transport of incoming MIDI clock.
+    FollowMidiClock(bool),
+    /// A clock message received on a MIDI input, with its timestamp in microseconds.
+    ClockIn(MidiMessage, u64),
+    AddTimer(u64, Period),
+    /// Remove a timer or a routine.
+    RemoveTimer(u64),
+    /// Start a routine from the next beat not scheduled yet.
+    Spawn(u64),
+    /// A routine yielded at a beat, waiting for the next one.
+    Resume(u64, Time, Wait),
+    /// What a timer or a routine played at a beat, to be played for a
+    /// number of beats, or a bar.
+    Fired(Time, Option<Time>, Fired),
+    /// Generate a stream with the Lua function registered under its name.
+    AddGenerator(String),
+    /// A cycle of a generated stream.
+    Generated(String, i64, streams::StreamContent),
+    GetTempo(Sender<ClockReply>),
+    GetSubscribers(Sender<ClockReply>),
+    /// Drop what was scheduled, release held notes and end the clock thread.
+    Exit,
+}
+
+/// Replies sent back by the clock thread to queries.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClockReply {
+    Tempo(f64),
+    Subscribers(usize),
 }
 
 impl Clock {
   pub fn new(
-     midi: Arc<Mutex<MidiConnexion>>,
-     receiver: Receiver<ClockControlMessage>, 
-     sender: Sender<ClockControlMessage>
+     link: Arc<AblLink>,
+     source: Box<dyn TimeSource>,
+     receiver: Receiver<ClockCommand>,
+     dispatcher: Sender<DispatcherMessage>,
+     timers: Timers,
+     lookahead: f64
   ) -> Self {
-    Self {
-      link: AblLink::new(120.0),
-      session_state: SessionState::new(),
+    let mut clock = Self {
+      link,
+      source,
       sync: true,
       running: true,
       quantum: 4.0,
-      snapshot: None,
-      receiver: receiver,
-      sender: sender,
-      midi: midi,
-      subscribers: Vec::new()
-    }
+      state: SharedClockState::default(),
+      receiver,
+      subscribers: Vec::new(),
+      scheduler: Scheduler::new(lookahead, dispatcher),
+      clock_input: None,
+      timers,
+      automation: None
+    };
+    clock.publish();
+    clock
   }
 
+  /// Add a stream, replacing the stream with the same name if there is one.
   pub fn add_subscriber(&mut self, stream: streams::Stream) {
-    self.subscribers.push(stream);
-  }
-
-  pub fn clear_subs(&mut self) {
-    self.subscribers.clear();
+    match self.subscribers.iter_mut().find(|s| s.name() == stream.name()) {
+      Some(existing) => *existing = stream,
+      None => self.subscribers.push(stream),
+    }
   }
 
-  pub fn get_clock_state(&mut self) -> ClockState {
-    self.capture_app_state();
-    let time = self.link.clock_micros();
-    let enabled = match self.link.is_enabled() {
-      true => "yes",
-      false => "no ",
+  /// The beat at which a change quantized to `quantize` must be applied, or
+  /// `None` when the clock is stopped and changes can be applied right away.
+  /// Swaps never happen before the end of the events already scheduled.
+  fn swap_beat(&self, quantize: Quantize) -> Option<Time> {
+    if !self.source.is_playing() {
+      return None;
     }
-    .to_string();
-    let num_peers = self.link.num_peers();
-    let start_stop = match self.link.is_start_stop_sync_enabled() {
-      true => "yes",
-      false => "no ",
+    let now = self.source.clock_micros();
+    let beat = self.source.beat_at_time(now, self.quantum);
+    let horizon = self.scheduler.horizon().unwrap_or_else(|| to_time(beat));
+    let span = match quantize {
+      Quantize::Now => return Some(horizon),
+      Quantize::Beat => 1.0,
+      Quantize::Bar => self.quantum,
+      Quantize::Bars(bars) => self.quantum * bars as f64,
+    };
+    let step = to_time(span);
+    if step <= Time::from_integer(0) {
+      return Some(horizon);
     }
-    .to_string();
-    let playing = match self.session_state.is_playing() {
-      true => "[playing]",
-      false => "[stopped]",
+    let phase = self.source.phase_at_time(now, span);
+    let mut boundary = to_time(beat - phase + span);
+    while boundary < horizon {
+      boundary += step;
     }
-    .to_string();
-    let tempo = self.session_state.tempo();
-    let beats = self.session_state.beat_at_time(time, self.quantum);
-    let phase = self.session_state.phase_at_time(time, self.quantum);
-    let mut metro = String::with_capacity(self.quantum as usize);
-    for i in 0..self.quantum as usize {
-      if i > phase as usize {
-        metro.push('O');
-      } else {
-        metro.push('X');
+    Some(boundary)
+  }
+
+  /// The stream called `name`, created silent if it does not exist yet.
+  fn subscriber(&mut self, name: String) -> &mut streams::Stream {
+    let index = match self.subscribers.iter().position(|s| s.name() == name) {
+      Some(index) => index,
+      None => {
+        self.subscribers.push(streams::Stream::new(name));
+        self.subscribers.len() - 1
       }
+    };
+    &mut self.subscribers[index]
+  }
+
+  /// Replace the pattern of a stream at the next quantized boundary.
+  pub fn swap_subscriber(&mut self, spec: StreamSpec) {
+    self.timers.remove_generator(&spec.name);
+    let at = self.swap_beat(spec.quantize);
+    let pattern = spec.content.into_pattern(self.quantum);
+    let stream = self.subscriber(spec.name);
+    match at {
+      Some(at) => stream.swap_at(at, pattern),
+      None => stream.set_pattern(pattern),
     }
+  }
+
+  /// Layer content on top of a stream at the next quantized boundary,
+  /// on top of any change already waiting for its turn.
+  pub fn update_subscriber(&mut self, spec: StreamSpec) {
+    let at = self.swap_beat(spec.quantize);
+    let layer = spec.content.into_pattern(self.quantum);
+    let stream = self.subscriber(spec.name);
+    let pattern = match stream.next_pattern() {
+      Some(current) => streams::Pattern::stack(vec![current.clone(), layer]),
+      None => layer,
+    };
+    match at {
+      Some(at) => stream.swap_at(at, pattern),
+      None => stream.set_pattern(pattern),
+    }
+  }
+
+  /// Stop a stream at the next quantized boundary.
+  pub fn remove_subscriber(&mut self, name: &str, quantize: Quantize) {
+    self.timers.remove_generator(name);
+    match self.swap_beat(quantize) {
+      Some(at) => {
+        if let Some(stream) = self.subscribers.iter_mut().find(|s| s.name() == name) {
+          stream.remove_at(at);
+        }
+      },
+      None => self.subscribers.retain(|s| s.name() != name),
+    }
+  }
+
+  pub fn clear_subs(&mut self) {
+    self.subscribers.clear();
+  }
+
+  /// The state the clock publishes, to be read from any thread.
+  pub fn state(&self) -> SharedClockState {
+    self.state.clone()
+  }
 
+  pub fn get_clock_state(&mut self) -> ClockState {
+    self.capture_app_state();
+    let micros = self.source.clock_micros();
     ClockState {
-      enabled,
-      num_peers,
-      start_stop,
-      playing,
-      tempo,
-      beats,
-      phase,
-      metro,
+      tempo: self.source.tempo(),
+      beat: self.source.beat_at_time(micros, self.quantum),
+      micros,
+      quantum: self.quantum,
+      playing: self.source.is_playing(),
+      peers: self.source.num_peers(),
+      source: self.source.kind().name(),
     }
   }
 
-  
+  /// Publish the current state.
+  pub fn publish(&mut self) {
+    let state = self.get_clock_state();
+    self.state.store(Arc::new(state));
+  }
+
   pub fn is_running(&self) -> bool {
     return self.running;
   }
 
   pub fn set_tempo(&mut self, tempo: f64) {
-    let time_stamp = self.link.clock_micros();
-    self.session_state.set_tempo(tempo, time_stamp);
-    self.link.commit_app_session_state(&self.session_state);
+    let time_stamp = self.source.clock_micros();
+    self.source.set_tempo(tempo, time_stamp);
+    self.automation = None;
     self.commit_app_state();
   }
 
-  // Make snapshots
-
-  pub fn make_snapshot(&mut self) {
-    self.snapshot = Some(self.get_clock_state());
-  }
-
-  pub fn print_snapshot(&self) {
-    match &self.snapshot {
-      Some(s) => {
-        println!("Tempo: {}", &s.tempo);
-        println!("Beats: {}", &s.beats);
-        println!("Phase: {}", &s.phase);
-        println!("Metro: {}", &s.metro);
-      }
-      None => println!("No snapshot available"),
+  /// Follow another timeline. The internal clock carries on from the beat
+  /// and tempo of Link; Link keeps its own timeline, peers being unknown
+  /// until discovery has run, so that joining never overrides a session.
+  /// Either way the beat may jump, so what was scheduled is dropped.
+  pub fn set_source(&mut self, kind: SourceKind) {
+    if self.source.kind() == kind {
+      return;
     }
+    self.capture_app_state();
+    let source: Box<dyn TimeSource> = match kind {
+      SourceKind::Internal => Box::new(InternalSource::follow(self.link.clone(), self.source.as_ref(), self.quantum)),
+      SourceKind::Link => Box::new(LinkSource::new(self.link.clone())),
+      SourceKind::Virtual => {
+        println!("Clock: virtual time is only used when rendering");
+        return;
+      },
+    };
+    // The Link source being replaced disables Link when dropped.
+    self.source = source;
+    self.scheduler.release();
+    self.timers.reset();
+    println!("Clock: following {}", kind.name());
   }
 
   pub fn sync(&mut self) {
@@ -153,44 +382,52 @@ impl Clock {
   }
 
   pub fn peers(&self) -> u64 {
-    return self.link.num_peers();
+    return self.source.num_peers();
   }
 
   pub fn play(&mut self) {
-    let time_stamp = self.link.clock_micros();
-    if self.session_state.is_playing() {
-      self.session_state.set_is_playing(false, time_stamp as u64);
+    let playing = !self.source.is_playing();
+    self.set_playing(playing);
+    self.report();
+  }
+
+  /// Start from the first beat, or stop and drop what was scheduled.
+  pub fn set_playing(&mut self, playing: bool) {
+    if playing == self.source.is_playing() {
+      return;
+    }
+    let time_stamp = self.source.clock_micros();
+    if playing {
+      self.source.start(time_stamp, 0., self.quantum);
     } else {
-      self.session_state.set_is_playing_and_request_beat_at_time(
-        true,
-        time_stamp as u64,
-        0.,
-        self.quantum);
+      self.source.stop(time_stamp);
+      self.scheduler.release();
+      self.timers.reset();
+      self.automation = None;
     }
     self.commit_app_state();
-    self.report();
   }
 
   pub fn report(&mut self) {
       self.capture_app_state();
-      let time = self.link.clock_micros();
+      let time = self.source.clock_micros();
       let enabled = match self.link.is_enabled() {
           true => "yes",
           false => "no ",
       }
       .to_string();
-      let num_peers = self.link.num_peers();
+      let num_peers = self.source.num_peers();
       let start_stop = match self.link.is_start_stop_sync_enabled() {
           true => "yes",
           false => "no ",
       };
-      let playing = match self.session_state.is_playing() {
+      let playing = match self.source.is_playing() {
           true => "[playing]",
           false => "[stopped]",
       };
-      let tempo = self.session_state.tempo();
-      let beats = self.session_state.beat_at_time(time, self.quantum);
-      let phase = self.session_state.phase_at_time(time, self.quantum);
+      let tempo = self.source.tempo();
+      let beats = self.source.beat_at_time(time, self.quantum);
+      let phase = self.source.phase_at_time(time, self.quantum);
       let mut metro = String::with_capacity(self.quantum as usize);
       for i in 0..self.quantum as usize {
           if i > phase as usize {
@@ -203,108 +440,265 @@ impl Clock {
            enabled, num_peers, self.quantum.trunc(), start_stop, playing, tempo, beats, metro);
   }
 
-  pub fn handle_messages(&mut self, recv: &ClockControlMessage) {
+  pub fn handle_messages(&mut self, command: ClockCommand) {
       self.capture_app_state();
-      match recv.name.as_str() {
-          "test" => {
+      match command {
+          ClockCommand::Test => {
             // Create a new test stream
-            let mut stream = streams::Stream::new("default".to_string(), self.midi.clone());
-            let beat = self.session_state.beat_at_time(self.link.clock_micros(), self.quantum);
-            stream.add_event(streams::Event::new(1.0, 2.0,  streams::BaseEventType::Tick, Vec::new()));
-            self.add_subscriber(stream);
-          }
-          "beats" => {
-            self.sender.send(ClockControlMessage {
-              name: "beats".to_string(),
-              args: vec![self.session_state.beat_at_time(self.link.clock_micros(), self.quantum).to_string()],
-            }).unwrap();
-          }
-          "subscribers" => {
-            self.sender.send(ClockControlMessage {
-              name: "subscribers".to_string(),
-              args: vec![self.subscribers.len().to_string()],
-            }).unwrap();
+            let event = streams::Event::new(
+              streams::Time::from_integer(1),
+              streams::Time::from_integer(2),
+              streams::BaseEventType::Tick,
+              Vec::new()
+            );
+            let spec = StreamSpec {
+              name: "default".to_string(),
+              content: streams::StreamContent::Events(vec![event]),
+              quantize: Quantize::Now
+            };
+            self.add_subscriber(spec.into_stream(self.quantum));
+          },
+          ClockCommand::AddStream(spec) => {
+            self.swap_subscriber(spec);
+          },
+          ClockCommand::UpdateStream(spec) => {
+            self.update_subscriber(spec);
+          },
+          ClockCommand::RemoveStream(name, quantize) => {
+            self.remove_subscriber(&name, quantize);
+          },
+          ClockCommand::SetMidiClock(enabled) => {
+            self.scheduler.set_midi_clock(enabled);
+          },
+          ClockCommand::FollowMidiClock(enabled) => {
+            self.clock_input = enabled.then(ClockInput::new);
+          },
+          ClockCommand::ClockIn(message, stamp) => {
+            self.follow_clock_in(message, stamp);
+          },
+          ClockCommand::AddTimer(id, period) => {
+            self.timers.add(id, period);
+          },
+          ClockCommand::RemoveTimer(id) => {
+            self.timers.remove(id);
+          },
+          ClockCommand::Spawn(id) => {
+            let beat = self.scheduler.horizon().unwrap_or_else(Time::zero);
+            self.timers.wake_at(id, beat);
+          },
+          ClockCommand::Resume(id, beat, wait) => {
+            self.timers.wake_at(id, wait.until(beat, self.quantum));
           },
-          "add_subscriber" => {
-            let stream = streams::Stream::new(recv.args[0].clone(), self.midi.clone());
-            self.add_subscriber(stream);
+          ClockCommand::Fired(beat, length, fired) => {
+            self.apply_fired(beat, length, fired);
           },
-          "sync" => {
+          ClockCommand::AddGenerator(name) => {
+            self.subscriber(name.clone());
+            self.timers.add_generator(name);
+          },
+          ClockCommand::Generated(name, cycle, content) => {
+            self.apply_generated(name, cycle, content);
+          },
+          ClockCommand::Sync => {
             self.sync();
           },
-          "play" => {
+          ClockCommand::Play => {
             self.play();
             self.commit_app_state();
           },
-          "peers" => {
-            self.sender.send(ClockControlMessage {
-              name: "peers".to_string(),
-              args: vec![self.link.num_peers().to_string()],
-            }).unwrap();
-          },
-          "get_tempo" => {
-            self.sender.send(ClockControlMessage {
-              name: "get_tempo".to_string(),
-              args: vec![self.session_state.tempo().to_string()],
-            }).unwrap();
-          },
-          "set_tempo" => {
-            let tempo = recv.args[0].parse::<f64>().unwrap();
+          ClockCommand::SetTempo(tempo) => {
             self.set_tempo(tempo);
             self.commit_app_state();
           },
-          "get_phase" => {
-            self.sender.send(ClockControlMessage {
-              name: "get_phase".to_string(),
-              args: vec![self.session_state.phase_at_time(self.link.clock_micros(), self.quantum).to_string()],
-            }).unwrap();
+          ClockCommand::Automate(points, quantize) => {
+            self.automate(points, quantize);
           },
-          "report" => {
+          ClockCommand::SetTimeSource(kind) => {
+            self.set_source(kind);
+          },
+          ClockCommand::Report => {
             self.report();
           },
-          _ => {
-            println!("Unknown command: {}", recv.name);
-          }
+          ClockCommand::GetTempo(reply) => {
+            let _ = reply.send(ClockReply::Tempo(self.source.tempo()));
+          },
+          ClockCommand::GetSubscribers(reply) => {
+            let _ = reply.send(ClockReply::Subscribers(self.subscribers.len()));
+          },
+          ClockCommand::Exit => {
+            self.scheduler.release();
+            self.running = false;
+          },
       }
   }
 
   pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-      self.link.enable_start_stop_sync(true);
-      self.link.enable(true);
-      let interval = Duration::from_millis(20);
+      let interval = Duration::from_millis(TICK);
       let mut next_time = Instant::now() + interval;
       loop {
-          let receive = self.receiver.try_recv();
-          match receive {
-              Ok(recv) => {
-                  self.handle_messages(&recv);
-              },
-              Err(_) => {}
-          }
-          for sub in &mut self.subscribers {
-            let beat = self.session_state.beat_at_time(self.link.clock_micros(), self.quantum);
-            let bar = beat / self.quantum;
-            sub.notify_tick(
-              self.quantum as f64,
-              beat,
-              bar,
-            );
-          }
+          self.step();
           if !self.is_running() {
               return Ok(());
           }
-          self.commit_app_state();
-          self.link.commit_app_session_state(&self.session_state);
-          sleep(next_time - Instant::now());
+          sleep(next_time.saturating_duration_since(Instant::now()));
           next_time += interval;
       }
   }
 
+  /// Handle pending commands and tick once, whatever the time. Virtual
+  /// time is advanced between steps rather than slept through.
+  pub fn step(&mut self) {
+      while let Ok(command) = self.receiver.try_recv() {
+          self.handle_messages(command);
+      }
+      self.tick();
+      self.publish();
+      self.commit_app_state();
+  }
+
+  /// The timeline followed.
+  pub fn source(&self) -> &dyn TimeSource {
+      self.source.as_ref()
+  }
+
+  /// Follow incoming MIDI clock, unless it is disabled or Link peers are
+  /// there to lead the session.
+  fn follow_clock_in(&mut self, message: MidiMessage, stamp: u64) {
+    let Some(input) = self.clock_input.as_mut() else {
+      return;
+    };
+    if self.source.num_peers() > 0 {
+      input.reset();
+      return;
+    }
+    let playing = self.source.is_playing();
+    match message {
+      MidiMessage::MidiClock => {
+        if let Some(tempo) = input.pulse(stamp) {
+          if (tempo - self.source.tempo()).abs() > 0.1 {
+            self.set_tempo(tempo);
+          }
+        }
+      },
+      MidiMessage::MidiStart | MidiMessage::MidiContinue if !playing => self.play(),
+      MidiMessage::MidiStop if playing => self.play(),
+      _ => {}
+    }
+  }
+
+  /// Schedule the events of the current window ahead of time.
+  pub fn tick(&mut self) {
+    self.capture_app_state();
+    if !self.source.is_playing() {
+      // Stopped since the last tick, possibly by a Link peer.
+      if self.scheduler.horizon().is_some() {
+        self.scheduler.release();
+        self.timers.reset();
+        self.automation = None;
+      }
+      return;
+    }
+    let now = self.source.clock_micros();
+    self.apply_automation(now);
+    let beat = self.source.beat_at_time(now, self.quantum);
+    let map = TempoMap::new(self.source.as_ref(), self.automation.as_ref(), self.quantum);
+    self.scheduler.schedule(&mut self.subscribers, &map, beat, self.quantum);
+    self.subscribers.retain(|s| !s.is_finished());
+    let tempo = self.source.tempo();
+    if let Some(horizon) = self.scheduler.horizon() {
+      self.timers.fire(to_time(beat), horizon + self.timers.lead(tempo), self.quantum, tempo);
+    }
+  }
+
+  /// Play a generated cycle from its first beat on, until the next one
+  /// replaces it. A cycle generated too late, after the scheduler went past
+  /// its first beat, only plays from the beat not scheduled yet.
+  fn apply_generated(&mut self, name: String, cycle: i64, content: streams::StreamContent) {
+    // Replaced by fixed content since the cycle was asked for.
+    if !self.timers.generates(&name) {
+      return;
+    }
+    let pattern = content.into_pattern(self.quantum);
+    let mut beat = Time::from_integer(cycle.into()) * to_time(self.quantum);
+    if let Some(horizon) = self.scheduler.horizon().filter(|horizon| *horizon > beat) {
+      println!("Clock: cycle {} of {} generated late, played from beat {}", cycle, name, horizon);
+      beat = horizon;
+    }
+    self.subscriber(name).swap_at(beat, pattern);
+  }
+
+  /// Play what a timer returned for `beat`: a tempo change right at that
+  /// beat, and events for `length` beats from there, a bar if not given.
+  /// When the Lua thread answered too late, after the scheduler went past
+  /// `beat`, the tempo changes from where it stands and the events that
+  /// should have started already are dropped: the rest was timed at the old
+  /// tempo, and the dispatcher would send them right away.
+  fn apply_fired(&mut self, beat: Time, length: Option<Time>, fired: Fired) {
+    if !self.source.is_playing() {
+      return;
+    }
+    let horizon = self.scheduler.horizon().unwrap_or(beat).max(beat);
+    if horizon > beat {
+      println!("Clock: callback for beat {} answered late, applied from beat {}", beat, horizon);
+    }
+    if let Some(tempo) = fired.tempo {
+      let now = self.source.clock_micros();
+      let at = self.source.time_at_beat(streams::to_f64(horizon), self.quantum).max(now);
+      self.source.set_tempo(tempo, at);
+      self.automation = None;
+      self.commit_app_state();
+    }
+    if let Some(content) = fired.content {
+      let pattern = content.into_single_pass(self.quantum);
+      let length = length.unwrap_or_else(|| to_time(self.quantum));
+      let mut occurrences = streams::Stream::once(&pattern, beat, length, self.quantum);
+      occurrences.retain(|occurrence| occurrence.event.begin() >= horizon);
+      let map = TempoMap::new(self.source.as_ref(), self.automation.as_ref(), self.quantum);
+      self.scheduler.play(occurrences, &map, self.quantum);
+    }
+  }
+
+  /// Follow `points` from the boundary of `quantize`, or from the first
+  /// beat when stopped. Replaces any ramp in progress.
+  fn automate(&mut self, points: Vec<TempoPoint>, quantize: Quantize) {
+    let start = self.swap_beat(quantize).unwrap_or_else(Time::zero);
+    let automation = match self.automation.take() {
+      // What comes before `start` may already be scheduled.
+      Some(automation) if automation.next().is_some_and(|next| next < start) => automation.then(&points, start),
+      _ => Automation::new(&points, start, self.source.tempo()),
+    };
+    self.automation = Some(automation);
+  }
+
+  /// Apply the changes of the automation reached by host time `now`, each
+  /// at its own beat, so that the timeline is exactly the one scheduled
+  /// against. Every change is shared with Link peers.
+  fn apply_automation(&mut self, now: i64) {
+    let Some(automation) = self.automation.as_mut() else {
+      return;
+    };
+    let beat = to_time(self.source.beat_at_time(now, self.quantum));
+    while let Some(at) = automation.next().filter(|at| *at <= beat) {
+      let time = self.source.time_at_beat(streams::to_f64(at), self.quantum);
+      self.source.set_tempo(automation.tempo_at(at), time);
+      automation.advance();
+    }
+    if automation.is_over() {
+      self.automation = None;
+    }
+    self.commit_app_state();
+  }
+
+  /// Beats to host time, ramps included.
+  pub fn tempo_map(&self) -> TempoMap<'_> {
+    TempoMap::new(self.source.as_ref(), self.automation.as_ref(), self.quantum)
+  }
+
   pub fn capture_app_state(&mut self) {
-    self.link.capture_app_session_state(&mut self.session_state);
+    self.source.capture();
   }
 
   pub fn commit_app_state(&mut self) {
-    self.link.commit_app_session_state(&self.session_state);
+    self.source.commit();
   }
 }
\ No newline at end of file
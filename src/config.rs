@@ -1,7 +1,94 @@
 use serde_derive::{Serialize, Deserialize};
+use std::collections::HashMap;
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+use crate::osc;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct EremitConfig {
     pub version: u8,
+    /// MIDI output port, or part of its name. The first port found when empty.
     pub port: String,
-}
\ No newline at end of file
+    /// Create a virtual "Eremit" output port instead of connecting to `port`.
+    pub virtual_output: bool,
+    /// How far ahead of the current beat the scheduler looks, in milliseconds.
+    pub lookahead: f64,
+    /// `link` to join the Link session on the network, `internal` to keep
+    /// time on our own.
+    pub time_source: String,
+    /// How long before their time Lua callbacks and stream generators are
+    /// called, on top of the lookahead, in milliseconds.
+    pub callback_lead: f64,
+    /// Latency compensation of the MIDI output, in milliseconds.
+    pub midi_latency: f64,
+    /// Send All Notes Off (CC 123) on every channel on top of the NoteOffs
+    /// sent when stopping, for devices that lose track of their notes.
+    pub all_notes_off: bool,
+    /// Send MIDI clock following the Link session.
+    pub midi_clock: bool,
+    /// MIDI input ports to listen to.
+    pub midi_inputs: Vec<String>,
+    /// Follow incoming MIDI clock when there are no Link peers.
+    pub follow_midi_clock: bool,
+    /// Latency compensation of the OSC output, in milliseconds.
+    pub osc_latency: f64,
+    /// Send OSC events as bundles timetagged ahead of time.
+    pub osc_bundles: bool,
+    /// Address the OSC remote control listens on, disabled when empty. It
+    /// runs any Lua code it is sent, so keep it on a local address.
+    pub osc_server: String,
+    /// Address editors send code to, `unix:<path>` for a Unix socket.
+    /// Disabled when empty.
+    pub eval_server: String,
+    /// Secret every request of the eval server must carry. Required when it
+    /// listens on TCP, where any local process or web page can reach it.
+    pub eval_token: String,
+    /// Extra MIDI outputs opened at startup, by alias: `volca = "volca beats"`.
+    /// Tables are kept last, TOML tables coming after plain values.
+    pub outputs: HashMap<String, String>,
+    /// OSC targets by alias, `default` being used by events naming none.
+    /// Addresses are `host:port`, or `tcp://host:port` for SLIP over TCP.
+    pub osc_targets: HashMap<String, String>,
+}
+
+impl EremitConfig {
+    /// The same without any port, server or target to open, for rendering.
+    pub fn offline(self) -> Self {
+        Self {
+            outputs: HashMap::new(),
+            midi_inputs: Vec::new(),
+            follow_midi_clock: false,
+            osc_server: String::new(),
+            eval_server: String::new(),
+            osc_targets: HashMap::new(),
+            ..self
+        }
+    }
+}
+
+impl Default for EremitConfig {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            port: String::new(),
+            virtual_output: false,
+            lookahead: 100.0,
+            time_source: "link".to_string(),
+            callback_lead: 250.0,
+            midi_latency: 0.0,
+            all_notes_off: false,
+            midi_clock: false,
+            midi_inputs: Vec::new(),
+            follow_midi_clock: false,
+            osc_latency: 0.0,
+            osc_bundles: true,
+            osc_server: String::new(),
+            eval_server: String::new(),
+            eval_token: String::new(),
+            outputs: HashMap::new(),
+            osc_targets: HashMap::from([
+                (osc::DEFAULT_TARGET.to_string(), osc::SUPERDIRT.to_string())
+            ]),
+        }
+    }
+}
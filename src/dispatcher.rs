@@ -0,0 +1,239 @@
+use rusty_link::AblLink;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt::{self, Display};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rosc::{OscMessage, OscTime};
+
+use crate::midi::{MidiMessage, MidiOutputs, MidiTarget};
+use crate::osc::{self, OscTargets};
+
+/// Below this distance to the next deadline (in microseconds) the dispatcher
+/// stops sleeping and spins, the OS scheduler being too coarse past that point.
+const SPIN_THRESHOLD: i64 = 1500;
+
+/// The outputs a message can leave the process through.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Output {
+    Midi,
+    Osc,
+}
+
+impl Output {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "midi" => Some(Output::Midi),
+            "osc" => Some(Output::Osc),
+            _ => None,
+        }
+    }
+}
+
+pub enum OutputMessage {
+    Midi(MidiTarget, MidiMessage),
+    /// A message for an OSC target, the default one when `None`.
+    Osc(Option<String>, OscMessage),
+}
+
+impl OutputMessage {
+    pub fn output(&self) -> Output {
+        match self {
+            OutputMessage::Midi(..) => Output::Midi,
+            OutputMessage::Osc(..) => Output::Osc,
+        }
+    }
+}
+
+impl Display for OutputMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputMessage::Midi(target, message) => {
+                let target = match target {
+                    MidiTarget::Default => "default",
+                    MidiTarget::Alias(alias) => alias,
+                    MidiTarget::All => "all",
+                };
+                write!(f, "midi {} {:?}", target, message)
+            },
+            OutputMessage::Osc(target, message) => {
+                write!(f, "osc {} {}", target.as_deref().unwrap_or("default"), message.addr)?;
+                for arg in &message.args {
+                    write!(f, " {:?}", arg)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+pub enum DispatcherMessage {
+    /// Send `message` at the given Link time (in microseconds).
+    Schedule(i64, OutputMessage),
+    /// Send `message` right away, ahead of the queue.
+    Now(OutputMessage),
+    /// Latency compensation for an output, in microseconds.
+    SetLatency(Output, i64),
+    /// Drop everything still waiting in the queue.
+    Clear,
+    /// Release every note still sounding, right away.
+    ReleaseNotes,
+    /// Reply once every message sent before this one was handled.
+    Ack(Sender<()>),
+}
+
+struct Entry {
+    deadline: i64,
+    order: u64,
+    message: OutputMessage,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline && self.order == other.order
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    // Reversed: the earliest deadline sits on top of the max-heap, ties are
+    // sent in the order they were scheduled.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+            .then_with(|| other.order.cmp(&self.order))
+    }
+}
+
+/// Output thread holding a priority queue of timestamped messages. Messages
+/// are fed ahead of time by the scheduler and leave the process as close as
+/// possible to their Link time, minus the latency of their output.
+pub struct Dispatcher {
+    link: Arc<AblLink>,
+    midi: Arc<Mutex<MidiOutputs>>,
+    osc: Arc<Mutex<OscTargets>>,
+    receiver: Receiver<DispatcherMessage>,
+    queue: BinaryHeap<Entry>,
+    latency: HashMap<Output, i64>,
+    order: u64,
+}
+
+impl Dispatcher {
+    /// Start the dispatcher thread and return the sender used to feed it.
+    pub fn spawn(
+        link: Arc<AblLink>,
+        midi: Arc<Mutex<MidiOutputs>>,
+        osc: Arc<Mutex<OscTargets>>,
+        latency: HashMap<Output, i64>
+    ) -> Sender<DispatcherMessage> {
+        let (sender, receiver) = mpsc::channel::<DispatcherMessage>();
+        let mut dispatcher = Self {
+            link,
+            midi,
+            osc,
+            receiver,
+            queue: BinaryHeap::new(),
+            latency,
+            order: 0,
+        };
+        thread::spawn(move || dispatcher.run());
+        sender
+    }
+
+    fn handle_message(&mut self, message: DispatcherMessage) {
+        match message {
+            DispatcherMessage::Schedule(time, message) => {
+                let latency = self.latency.get(&message.output()).copied().unwrap_or(0);
+                let deadline = time - latency;
+                match message {
+                    // Bundles are timetagged, the receiver takes care of timing.
+                    OutputMessage::Osc(target, message) if self.osc.lock().unwrap().bundles() => {
+                        let timetag = osc::timetag(deadline, self.link.clock_micros());
+                        self.send_osc(target, message, timetag);
+                    },
+                    message => {
+                        self.order += 1;
+                        self.queue.push(Entry {
+                            deadline,
+                            order: self.order,
+                            message,
+                        });
+                    },
+                }
+            },
+            DispatcherMessage::Now(message) => {
+                self.send(message);
+            },
+            DispatcherMessage::SetLatency(output, latency) => {
+                self.latency.insert(output, latency);
+            },
+            DispatcherMessage::Clear => {
+                self.queue.clear();
+            },
+            DispatcherMessage::ReleaseNotes => {
+                if let Err(err) = self.midi.lock().unwrap().release_notes() {
+                    println!("MIDI error: {}", err);
+                }
+            },
+            DispatcherMessage::Ack(reply) => {
+                let _ = reply.send(());
+            },
+        }
+    }
+
+    fn send_osc(&self, target: Option<String>, message: OscMessage, timetag: Option<OscTime>) {
+        if let Err(err) = self.osc.lock().unwrap().send(target.as_deref(), message, timetag) {
+            println!("OSC error: {}", err);
+        }
+    }
+
+    fn send(&mut self, message: OutputMessage) {
+        match message {
+            OutputMessage::Osc(target, message) => self.send_osc(target, message, None),
+            OutputMessage::Midi(target, message) => {
+                if let Err(err) = self.midi.lock().unwrap().send(&target, message) {
+                    println!("MIDI error: {}", err);
+                }
+            },
+        }
+    }
+
+    fn run(&mut self) {
+        loop {
+            while let Ok(message) = self.receiver.try_recv() {
+                self.handle_message(message);
+            }
+            let Some(deadline) = self.queue.peek().map(|e| e.deadline) else {
+                // Nothing to send, sleep until the scheduler feeds us.
+                match self.receiver.recv() {
+                    Ok(message) => self.handle_message(message),
+                    Err(_) => return,
+                }
+                continue;
+            };
+            let remaining = deadline - self.link.clock_micros();
+            if remaining <= 0 {
+                let entry = self.queue.pop().unwrap();
+                self.send(entry.message);
+            } else if remaining > SPIN_THRESHOLD {
+                let timeout = Duration::from_micros((remaining - SPIN_THRESHOLD) as u64);
+                match self.receiver.recv_timeout(timeout) {
+                    Ok(message) => self.handle_message(message),
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            } else {
+                std::hint::spin_loop();
+            }
+        }
+    }
+}
@@ -1,64 +1,140 @@
 use mlua::prelude::*;
-use mlua::MultiValue;
+use mlua::{MultiValue, Variadic};
+use serde_derive::Serialize;
 use rustyline::DefaultEditor;
 use mlua::Result as LuaResult;
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+pub mod server;
+
+/// A function run on the Lua thread.
+pub type LuaTask = Box<dyn FnOnce(&Lua) -> LuaResult<()> + Send>;
+
+/// Code to evaluate, and where to send the outcome.
+pub struct EvalRequest {
+    pub code: String,
+    /// Chunk name, as it shows in error messages.
+    pub name: String,
+    /// Collect what the chunk prints in the evaluation rather than
+    /// writing it to the terminal.
+    pub capture: bool,
+    pub reply: Sender<Evaluation>,
+}
+
+/// Work for the Lua thread. Lua state never leaves this thread: the prompt
+/// and the other threads talk to it through these events.
+pub enum InterpreterEvent {
+    Eval(EvalRequest),
+    /// Something to run with the Lua state, such as a user callback.
+    Call(LuaTask),
+    /// The prompt was closed.
+    Exit,
+}
+
+/// Handle on the queue of the Lua thread, shared by the prompt, the
+/// servers and the callbacks. Work is run in the order it was submitted.
+#[derive(Clone)]
+pub struct EvalQueue {
+    sender: Sender<InterpreterEvent>,
+}
+
+impl EvalQueue {
+    /// Submit a chunk, its printed output being captured. The evaluation
+    /// arrives on the receiver once the Lua thread got to it.
+    pub fn submit(&self, code: String, name: &str) -> Receiver<Evaluation> {
+        self.request(code, name, true)
+    }
+
+    /// Submit a chunk and wait for its evaluation.
+    pub fn evaluate(&self, code: String, name: &str) -> Evaluation {
+        self.submit(code, name).recv().unwrap_or_else(|_| Evaluation::stopped())
+    }
+
+    fn request(&self, code: String, name: &str, capture: bool) -> Receiver<Evaluation> {
+        let (reply, receiver) = mpsc::channel::<Evaluation>();
+        let request = EvalRequest { code, name: name.to_string(), capture, reply };
+        // When the Lua thread is gone, the reply sender is dropped with the
+        // request and the receiver reports it.
+        let _ = self.sender.send(InterpreterEvent::Eval(request));
+        receiver
+    }
+
+    /// Run `task` on the Lua thread, its errors being reported there.
+    pub fn call(&self, task: LuaTask) -> Result<(), String> {
+        self.sender.send(InterpreterEvent::Call(task))
+            .map_err(|_| "the interpreter is not running".to_string())
+    }
+
+    /// Wait for the Lua thread to be done with what came before.
+    pub fn sync(&self) -> Result<(), String> {
+        let (reply, receiver) = mpsc::channel::<()>();
+        self.call(Box::new(move |_lua| {
+            let _ = reply.send(());
+            Ok(())
+        }))?;
+        receiver.recv().map_err(|_| "the interpreter is not running".to_string())
+    }
+
+    /// Stop the Lua thread once it is done with what came before.
+    pub fn exit(&self) {
+        let _ = self.sender.send(InterpreterEvent::Exit);
+    }
+}
 
 pub struct Interpreter {
     pub lua: Lua,
     exit: Arc<Mutex<bool>>,
-    editor: DefaultEditor
+    editor: Option<DefaultEditor>,
+    sender: Sender<InterpreterEvent>,
+    receiver: Receiver<InterpreterEvent>
 }
 
 impl Interpreter {
     pub fn new() -> Self {
         let exit = Arc::new(Mutex::new(false));
         let lua = Lua::new();
+        install_print(&lua).expect("Failed to install print");
         let editor = DefaultEditor::new().expect("Failed to create editor");
+        let (sender, receiver) = mpsc::channel::<InterpreterEvent>();
         Interpreter {
             lua,
             exit,
-            editor,
+            editor: Some(editor),
+            sender,
+            receiver,
         }
     }
 
+    /// A handle to hand work to the Lua thread from anywhere.
+    pub fn queue(&self) -> EvalQueue {
+        EvalQueue { sender: self.sender.clone() }
+    }
+
+    /// Read lines on a separate thread and handle events until the prompt
+    /// is closed.
     pub fn run(&mut self) -> LuaResult<()> {
-        loop {
-            let mut prompt = "> ";
-            let mut line = String::new();
-    
-            loop {
-                match self.editor.readline(prompt) {
-                    Ok(input) => line.push_str(&input),
-                    Err(_) => return Ok(()),
-                }
-    
-                match self.lua.load(&line).eval::<MultiValue>() {
-                    Ok(values) => {
-                        self.editor.add_history_entry(line).unwrap();
-                        println!(
-                            "{}",
-                            values
-                                .iter()
-                                .map(|value| format!("{:#?}", value))
-                                .collect::<Vec<_>>()
-                                .join("\t")
-                        );
-                        break;
-                    }
-                    Err(mlua::Error::SyntaxError {
-                        incomplete_input: true,
-                        ..
-                    }) => {
-                        // continue reading input and append it to `line`
-                        line.push_str("\n"); // separate input lines
-                        prompt = ">> ";
-                    }
-                    Err(e) => {
+        let editor = self.editor.take().expect("The interpreter is already running");
+        let queue = self.queue();
+        thread::spawn(move || read_lines(editor, queue));
+        self.serve()
+    }
+
+    /// Handle events until told to exit, without a prompt.
+    pub fn serve(&mut self) -> LuaResult<()> {
+        while let Ok(event) = self.receiver.recv() {
+            match event {
+                InterpreterEvent::Eval(request) => {
+                    let evaluation = evaluate(&self.lua, &request.name, &request.code, request.capture);
+                    let _ = request.reply.send(evaluation);
+                },
+                InterpreterEvent::Call(function) => {
+                    if let Err(e) = function(&self.lua) {
                         eprintln!("error: {}", e);
-                        break;
                     }
-                }
+                },
+                InterpreterEvent::Exit => break,
             }
         }
         Ok(())
@@ -94,4 +170,127 @@ impl Interpreter {
         Ok(())
     }
 
-}
\ No newline at end of file
+}
+
+/// The outcome of an evaluation.
+#[derive(Debug, Default, Serialize)]
+pub struct Evaluation {
+    /// The values of the chunk, formatted as at the prompt.
+    pub values: Vec<String>,
+    /// Everything the chunk printed, when captured.
+    pub output: String,
+    pub error: Option<String>,
+    /// Line of the chunk the error points at, when Lua tells.
+    pub line: Option<usize>,
+    /// The chunk ended before it was complete.
+    #[serde(skip)]
+    pub incomplete: bool,
+}
+
+impl Evaluation {
+    fn stopped() -> Self {
+        Self {
+            error: Some("the interpreter is not running".to_string()),
+            ..Self::default()
+        }
+    }
+}
+
+/// What the evaluation running prints, when it is captured.
+struct Captured(String);
+
+/// Define `print` once and for all: functions keeping it, or redefining it,
+/// never see it change. It writes to the evaluation running when that one
+/// captures its output, and to the terminal otherwise.
+fn install_print(lua: &Lua) -> LuaResult<()> {
+    let print = lua.create_function(|lua, args: Variadic<LuaValue>| {
+        let tostring: LuaFunction = lua.globals().get("tostring")?;
+        let mut words = Vec::new();
+        for arg in args {
+            words.push(tostring.call::<_, String>(arg)?);
+        }
+        let line = words.join("\t");
+        match lua.app_data_mut::<Captured>() {
+            Some(mut captured) => {
+                captured.0.push_str(&line);
+                captured.0.push('\n');
+            },
+            None => println!("{}", line),
+        }
+        Ok(())
+    })?;
+    lua.globals().set("print", print)
+}
+
+/// Evaluate a chunk named `name`, capturing what it prints instead of
+/// writing it to the terminal if asked to.
+pub fn evaluate(lua: &Lua, name: &str, code: &str, capture: bool) -> Evaluation {
+    let mut evaluation = Evaluation::default();
+    if capture {
+        lua.set_app_data(Captured(String::new()));
+    }
+    let result = lua.load(code)
+        .set_name(format!("={}", name))
+        .eval::<MultiValue>()
+        .map(|values| values.iter().map(|value| format!("{:#?}", value)).collect::<Vec<_>>());
+    if capture {
+        evaluation.output = lua.remove_app_data::<Captured>().map(|captured| captured.0).unwrap_or_default();
+    }
+    match result {
+        Ok(values) => evaluation.values = values,
+        Err(err) => {
+            evaluation.incomplete = matches!(err, LuaError::SyntaxError { incomplete_input: true, .. });
+            let message = err.to_string();
+            evaluation.line = error_line(&message, name);
+            evaluation.error = Some(message);
+        },
+    }
+    evaluation
+}
+
+/// The line of the first `name:<line>:` location in an error message.
+fn error_line(message: &str, name: &str) -> Option<usize> {
+    let location = format!("{}:", name);
+    message.match_indices(&location).find_map(|(index, _)| {
+        let rest = &message[index + location.len()..];
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        match rest[digits..].starts_with(':') {
+            true => rest[..digits].parse().ok(),
+            false => None,
+        }
+    })
+}
+
+/// The prompt: send every line to the Lua thread and wait for its verdict,
+/// continuing lines that are not complete chunks yet.
+fn read_lines(mut editor: DefaultEditor, queue: EvalQueue) {
+    let mut prompt = "> ";
+    let mut line = String::new();
+    loop {
+        match editor.readline(prompt) {
+            Ok(input) => line.push_str(&input),
+            Err(_) => {
+                queue.exit();
+                return;
+            }
+        }
+        let Ok(evaluation) = queue.request(line.clone(), "stdin", false).recv() else {
+            return;
+        };
+        if evaluation.incomplete {
+            // continue reading input and append it to `line`
+            line.push('\n'); // separate input lines
+            prompt = ">> ";
+            continue;
+        }
+        match evaluation.error {
+            Some(error) => eprintln!("error: {}", error),
+            None => {
+                println!("{}", evaluation.values.join("\t"));
+                let _ = editor.add_history_entry(line.as_str());
+            },
+        }
+        line.clear();
+        prompt = "> ";
+    }
+}
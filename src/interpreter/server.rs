@@ -0,0 +1,151 @@
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::thread;
+
+use crate::interpreter::{EvalQueue, Evaluation};
+
+/// Name given to chunks sent without one, as it shows in error messages.
+const DEFAULT_CHUNK: &str = "eval";
+
+/// A code block sent by an editor, one JSON object per line:
+/// `{"id": 1, "code": "print(1)", "name": "live.lua", "token": "..."}`.
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    code: String,
+    name: Option<String>,
+    #[serde(default)]
+    token: String,
+}
+
+/// The reply to a request, one JSON object per line as well:
+/// `{"id": 1, "values": [], "output": "1\n", "error": null, "line": null}`.
+#[derive(Debug, Serialize)]
+struct Reply {
+    id: Value,
+    #[serde(flatten)]
+    evaluation: Evaluation,
+}
+
+/// Evaluation server for editor plugins, listening on a Unix socket
+/// (`unix:/tmp/eremit.sock`), only its owner can connect to, or on a TCP
+/// address (`127.0.0.1:7778`) with a token. Code runs in the Lua state of
+/// the prompt, one request at a time.
+pub struct EvalServer;
+
+impl EvalServer {
+    /// Requests must carry `token`, unless it is empty on a Unix socket.
+    pub fn spawn(address: &str, token: &str, queue: EvalQueue) -> Result<(), Box<dyn Error>> {
+        let token = token.to_string();
+        match address.strip_prefix("unix:") {
+            Some(path) => spawn_unix(path, token, queue)?,
+            None => {
+                if token.is_empty() {
+                    return Err("a TCP eval server needs an eval_token, or use unix:<path>".into());
+                }
+                let listener = TcpListener::bind(address)?;
+                thread::spawn(move || {
+                    for stream in listener.incoming().flatten() {
+                        let Ok(writer) = stream.try_clone() else {
+                            continue;
+                        };
+                        let (queue, token) = (queue.clone(), token.clone());
+                        thread::spawn(move || serve(BufReader::new(stream), writer, queue, &token));
+                    }
+                });
+            },
+        }
+        println!("Listening to editors on {}", address);
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn spawn_unix(path: &str, token: String, queue: EvalQueue) -> Result<(), Box<dyn Error>> {
+    use std::fs::{self, DirBuilder, Permissions};
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+    // A socket left behind by a previous run would make bind fail, anything
+    // else is not ours to remove.
+    let path = Path::new(path);
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket", path.display()).into());
+        }
+        fs::remove_file(path)?;
+    }
+    // Bound in a directory only we can enter, then moved in place once only
+    // we can connect.
+    let private = path.with_file_name(format!(".eremit-{}", std::process::id()));
+    DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("eval.sock");
+    let listener = UnixListener::bind(&bound)
+        .and_then(|listener| {
+            fs::set_permissions(&bound, Permissions::from_mode(0o600))?;
+            fs::rename(&bound, path)?;
+            Ok(listener)
+        });
+    let _ = fs::remove_file(&bound);
+    fs::remove_dir(&private)?;
+    let listener = listener?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let Ok(writer) = stream.try_clone() else {
+                continue;
+            };
+            let (queue, token) = (queue.clone(), token.clone());
+            thread::spawn(move || serve(BufReader::new(stream), writer, queue, &token));
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn spawn_unix(_path: &str, _token: String, _queue: EvalQueue) -> Result<(), Box<dyn Error>> {
+    Err("Unix sockets are not supported on this platform".into())
+}
+
+/// Answer the requests of one client until it disconnects. The first line
+/// that is not a valid request, with the right token, closes the connection:
+/// whatever sent it is not an editor, possibly a web page speaking HTTP.
+fn serve(reader: impl BufRead, mut writer: impl Write, queue: EvalQueue, token: &str) {
+    for line in reader.lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = serde_json::from_str::<Request>(&line)
+            .map_err(|err| format!("invalid request: {}", err))
+            .and_then(|request| match token.is_empty() || request.token == token {
+                true => Ok(request),
+                false => Err("invalid token".to_string()),
+            });
+        let (reply, valid) = match request {
+            Ok(request) => (Reply {
+                id: request.id,
+                evaluation: queue.evaluate(request.code, request.name.as_deref().unwrap_or(DEFAULT_CHUNK)),
+            }, true),
+            Err(error) => (Reply {
+                id: Value::Null,
+                evaluation: Evaluation {
+                    error: Some(error),
+                    ..Evaluation::default()
+                },
+            }, false),
+        };
+        let Ok(mut encoded) = serde_json::to_string(&reply) else {
+            return;
+        };
+        encoded.push('\n');
+        if writer.write_all(encoded.as_bytes()).is_err() || !valid {
+            return;
+        }
+    }
+}
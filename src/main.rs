@@ -1,198 +1,561 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
-use num::{rational::Ratio, ToPrimitive};
 use std::error::Error;
 use mlua::Lua;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc;
 use mlua::Result as LuaResult;
+use mlua::Error as LuaError;
+use mlua::Table as LuaTable;
+use mlua::Function as LuaFunction;
+use mlua::Variadic;
+use mlua::Value as LuaValue;
+use mlua::FromLua;
+use rosc::OscMessage;
 mod ascii;
 mod midi;
 mod clock;
 mod interpreter;
 mod config;
 mod streams;
+mod scheduler;
+mod dispatcher;
+mod mininotation;
+mod osc;
+mod timers;
+mod routines;
+mod time_source;
+mod render;
+mod tempo;
 use std::thread;
+use std::collections::HashMap;
+use rusty_link::AblLink;
 
-use crate::midi::MidiConnexion;
+use crate::midi::MidiOutputs;
+use crate::midi::input::{self as midi_input, MidiInputs};
+use crate::dispatcher::{DispatcherMessage, Output};
+use crate::clock::{ClockCommand, ClockReply, Quantize, StreamSpec};
+use crate::streams::{Event, Param, Pattern, StreamContent};
+use crate::mininotation::Notation;
+use crate::osc::server::OscServer;
+use crate::interpreter::server::EvalServer;
+use crate::timers::{Period, Timers};
+use crate::time_source::{Host, InternalSource, SourceKind, VirtualTime};
+use crate::render::{Render, RenderArgs};
+use crate::tempo::{Curve, TempoPoint};
+
+/// Send a command to the clock thread.
+fn send_to_clock(sender: &mpsc::Sender<ClockCommand>, command: ClockCommand) -> LuaResult<()> {
+    sender.send(command)
+        .map_err(|_| LuaError::RuntimeError("the clock is not running".to_string()))
+}
+
+/// Send a query to the clock thread and wait for the reply on its own channel.
+fn query_clock(
+    sender: &mpsc::Sender<ClockCommand>,
+    query: fn(mpsc::Sender<ClockReply>) -> ClockCommand
+) -> LuaResult<ClockReply> {
+    let (reply_sender, reply_receiver) = mpsc::channel::<ClockReply>();
+    send_to_clock(sender, query(reply_sender))?;
+    reply_receiver.recv()
+        .map_err(|_| LuaError::RuntimeError("the clock did not reply".to_string()))
+}
+
+fn unexpected_reply(reply: ClockReply) -> LuaError {
+    LuaError::RuntimeError(format!("unexpected reply from the clock: {:?}", reply))
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
-    println!("{}", ascii::BANNER);
-    let cfg: config::EremitConfig = confy::load("eremit", None)?;
-    let midi: Arc<Mutex<MidiConnexion>> = Arc::new(Mutex::new(midi::MidiConnexion::new(cfg.port)));
-    let (sender_to_clock, receiver_for_clock) = mpsc::channel::<clock::ClockControlMessage>();
-    let (sender_from_clock, receiver_for_main) = mpsc::channel::<clock::ClockControlMessage>();
-    let receiver_for_main = Arc::new(Mutex::new(receiver_for_main));
-    let clock = Arc::new(Mutex::new(clock::Clock::new(midi, receiver_for_clock, sender_from_clock)));
-    let clock_clone = clock.clone();
-    thread::spawn(move || {
-        let _ = clock_clone.lock().unwrap().run();
-    });
+    // `Eremit render <file.lua> [bars]` plays a file on a virtual clock and
+    // prints what would have been sent, without waiting nor opening outputs.
+    let args: Vec<String> = std::env::args().collect();
+    let render = match args.get(1).map(String::as_str) {
+        Some("render") => Some(RenderArgs::parse(&args[2..])?),
+        _ => None,
+    };
+    if render.is_none() {
+        println!("{}", ascii::BANNER);
+    }
+    let mut cfg: config::EremitConfig = confy::load("eremit", None)?;
+    if render.is_some() {
+        cfg = cfg.offline();
+    }
+    let mut outputs = MidiOutputs::new();
+    outputs.set_all_notes_off(cfg.all_notes_off);
+    if render.is_some() {
+        // Nothing is played when rendering.
+    } else if cfg.virtual_output {
+        if let Err(err) = outputs.open_virtual(midi::DEFAULT_OUTPUT, "Eremit") {
+            println!("MIDI output error ({}): {}", midi::DEFAULT_OUTPUT, err);
+        }
+    } else {
+        outputs.open_default(&cfg.port);
+    }
+    for (alias, port) in &cfg.outputs {
+        if let Err(err) = outputs.open(alias, port) {
+            println!("MIDI output error ({}): {}, waiting for it to appear", alias, err);
+        }
+    }
+    let midi: Arc<Mutex<MidiOutputs>> = Arc::new(Mutex::new(outputs));
+    MidiOutputs::watch(midi.clone());
+    let link = Arc::new(AblLink::new(120.0));
+    let mut targets = osc::OscTargets::new()?;
+    targets.set_bundles(cfg.osc_bundles);
+    for (alias, address) in &cfg.osc_targets {
+        if let Err(err) = targets.set_target(alias, address) {
+            println!("OSC error ({}): {}", alias, err);
+        }
+    }
+    let targets = Arc::new(Mutex::new(targets));
+    let latency = HashMap::from([
+        (Output::Midi, (cfg.midi_latency * 1000.0) as i64),
+        (Output::Osc, (cfg.osc_latency * 1000.0) as i64),
+    ]);
+    // When rendering, the clock sends to us rather than to the dispatcher.
+    let (dispatcher, rendered) = match render {
+        Some(_) => {
+            let (sender, receiver) = mpsc::channel::<DispatcherMessage>();
+            (sender, Some(receiver))
+        },
+        None => (dispatcher::Dispatcher::spawn(link.clone(), midi.clone(), targets.clone(), latency), None),
+    };
     let mut interpreter = interpreter::Interpreter::new();
+    let (sender_to_clock, receiver_for_clock) = mpsc::channel::<ClockCommand>();
+    let timers = Timers::new(interpreter.queue(), sender_to_clock.clone(), cfg.callback_lead);
+    let kind = SourceKind::from_name(&cfg.time_source).unwrap_or_else(|| {
+        println!("Unknown time source {:?}, using Link", cfg.time_source);
+        SourceKind::Link
+    });
+    let virtual_time = VirtualTime::default();
+    let source = match render {
+        Some(_) => Box::new(InternalSource::on_virtual_time(virtual_time.clone(), 120.0)),
+        None => time_source::create(kind, link.clone(), 120.0),
+    };
+    // Where "now" is read by Lua: every source but the virtual one uses the
+    // host clock of Link.
+    let host = match render {
+        Some(_) => Host::Virtual(virtual_time.clone()),
+        None => Host::System(link.clone()),
+    };
+    let mut clock = clock::Clock::new(
+        link.clone(), source, receiver_for_clock, dispatcher.clone(), timers, cfg.lookahead
+    );
+    let clock_state = clock.state();
+    if cfg.midi_clock {
+        sender_to_clock.send(ClockCommand::SetMidiClock(true))?;
+    }
+    if cfg.follow_midi_clock {
+        sender_to_clock.send(ClockCommand::FollowMidiClock(true))?;
+    }
+    let rendering = render.is_some();
+    let clock_thread = match (render, rendered) {
+        (Some(args), Some(output)) => {
+            let queue = interpreter.queue();
+            let render = Render::new(clock, virtual_time, output).with_queue(queue.clone());
+            thread::spawn(move || render::run(render, args, queue))
+        },
+        _ => {
+            thread::spawn(move || {
+                let _ = clock.run();
+            })
+        },
+    };
+    let midi_inputs = MidiInputs::open(&cfg.midi_inputs, interpreter.queue(), sender_to_clock.clone());
+    if !cfg.osc_server.is_empty() {
+        if let Err(err) = OscServer::spawn(&cfg.osc_server, interpreter.queue(), sender_to_clock.clone()) {
+            println!("OSC server error ({}): {}", cfg.osc_server, err);
+        }
+    }
+    if !cfg.eval_server.is_empty() {
+        if let Err(err) = EvalServer::spawn(&cfg.eval_server, &cfg.eval_token, interpreter.queue()) {
+            println!("Eval server error ({}): {}", cfg.eval_server, err);
+        }
+    }
     let _ = interpreter.register_function("report", {
         let cloned_sender = sender_to_clock.clone();
-            move |_lua: &Lua, _args: ()| -> LuaResult<()> {
-                cloned_sender.send(clock::ClockControlMessage {
-                    name: "report".to_string(),
-                    args: vec![],
-                }).unwrap();
-            Ok(())
+        move |_lua: &Lua, _args: ()| -> LuaResult<()> {
+            send_to_clock(&cloned_sender, ClockCommand::Report)
         }
     });
+    // Time queries read the state published by the clock at its last tick,
+    // extrapolated to now on the host clock of the source.
     let _ = interpreter.register_function("get_tempo", {
-        let cloned_sender = sender_to_clock.clone();
-        let cloned_receiver = receiver_for_main.clone();
-        move |_lua: &Lua, _args: ()| -> LuaResult<f32> {
-            cloned_sender.send(clock::ClockControlMessage {
-                name: "get_tempo".to_string(),
-                args: vec![],
-            }).unwrap();
-            let recv = cloned_receiver.lock().unwrap().recv().unwrap();
-            match recv.name.as_str() {
-                "get_tempo" => {
-                    Ok(recv.args[0].parse::<f32>().unwrap())
-                },
-                _ => {
-                    println!("Unknown command: {}", recv.name);
-                    Ok(0 as f32)
-                }
-            }
+        let clock_state = clock_state.clone();
+        move |_lua: &Lua, _args: ()| -> LuaResult<f64> {
+            Ok(clock_state.load().tempo)
         }
     });
     let _ = interpreter.register_function("beat", {
-        let cloned_sender = sender_to_clock.clone();
-        let cloned_receiver = receiver_for_main.clone();
+        let (clock_state, host) = (clock_state.clone(), host.clone());
         move |_lua: &Lua, _args: ()| -> LuaResult<f64> {
-            cloned_sender.send(clock::ClockControlMessage {
-                name: "beats".to_string(),
-                args: vec![],
-            }).unwrap();
-            let recv = cloned_receiver.lock().unwrap().recv().unwrap();
-            match recv.name.as_str() {
-                "beats" => {
-                    Ok(recv.args[0].parse::<f64>().unwrap())
-                },
-                _ => {
-                    println!("Unknown command: {}", recv.name);
-                    Ok(0 as f64)
-                }
-            }
+            Ok(clock_state.load().beat_at(host.now()))
         }
     });
     let _ = interpreter.register_function("get_phase", {
-        let cloned_sender = sender_to_clock.clone();
-        let cloned_receiver = receiver_for_main.clone();
+        let (clock_state, host) = (clock_state.clone(), host.clone());
         move |_lua: &Lua, _args: ()| -> LuaResult<f64> {
-            cloned_sender.send(clock::ClockControlMessage {
-                name: "get_phase".to_string(),
-                args: vec![],
-            }).unwrap();
-            let recv = cloned_receiver.lock().unwrap().recv().unwrap();
-            match recv.name.as_str() {
-                "get_phase" => {
-                    Ok(recv.args[0].parse::<f64>().unwrap())
-                },
-                _ => {
-                    println!("Unknown command: {}", recv.name);
-                    Ok(0 as f64)
-                }
-            }
+            Ok(clock_state.load().phase_at(host.now()))
         }
     });
     let _ = interpreter.register_function("set_tempo", {
         let cloned_sender = sender_to_clock.clone();
-        move |_lua: &Lua, _args: (f64,)| -> LuaResult<()> {
-            cloned_sender.send(clock::ClockControlMessage {
-                name: "set_tempo".to_string(),
-                args: vec![_args.0.to_string()],
-            }).unwrap();
-            Ok(())
+        move |_lua: &Lua, args: (f64,)| -> LuaResult<()> {
+            send_to_clock(&cloned_sender, ClockCommand::SetTempo(args.0))
+        }
+    });
+    // `tempo_ramp(140, 8, "exp")` reaches 140 bpm in 8 beats.
+    let _ = interpreter.register_function("tempo_ramp", {
+        let cloned_sender = sender_to_clock.clone();
+        move |_lua: &Lua, args: (f64, f64, Curve)| -> LuaResult<()> {
+            let (tempo, beats, curve) = args;
+            if tempo <= 0.0 || beats < 0.0 {
+                return Err(LuaError::RuntimeError("the tempo must be positive, and the number of beats too".to_string()));
+            }
+            let point = TempoPoint { beat: streams::to_time(beats), tempo, curve };
+            send_to_clock(&cloned_sender, ClockCommand::Automate(vec![point], Quantize::Now))
+        }
+    });
+    // `tempo_lane({{16, 140}, {32, 90, "exp"}, {48, 120, "step"}})`, beats
+    // counted from the next bar by default.
+    let _ = interpreter.register_function("tempo_lane", {
+        let cloned_sender = sender_to_clock.clone();
+        move |_lua: &Lua, args: (Vec<TempoPoint>, Quantize)| -> LuaResult<()> {
+            send_to_clock(&cloned_sender, ClockCommand::Automate(args.0, args.1))
         }
     });
     let _ = interpreter.register_function("play", {
         let cloned_sender = sender_to_clock.clone();
         move |_lua: &Lua, _args: ()| -> LuaResult<()> {
-            cloned_sender.send(clock::ClockControlMessage {
-                name: "play".to_string(),
-                args: vec![],
-            }).unwrap();
-            Ok(())
+            send_to_clock(&cloned_sender, ClockCommand::Play)
         }
     });
     let _ = interpreter.register_function("sync", {
         let cloned_sender = sender_to_clock.clone();
         move |_lua: &Lua, _args: ()| -> LuaResult<()> {
-            cloned_sender.send(clock::ClockControlMessage {
-                name: "sync".to_string(),
-                args: vec![],
-            }).unwrap();
-            Ok(())
+            send_to_clock(&cloned_sender, ClockCommand::Sync)
         }
     });
     let _ = interpreter.register_function("peers", {
+        let clock_state = clock_state.clone();
+        move |_lua: &Lua, _args: ()| -> LuaResult<u64> {
+            Ok(clock_state.load().peers)
+        }
+    });
+    // `clock_source("internal")` stops following Link, `clock_source()`
+    // tells which one is followed. Either returns the source followed from
+    // then on.
+    let _ = interpreter.register_function("clock_source", {
+        let (cloned_sender, clock_state) = (sender_to_clock.clone(), clock_state.clone());
+        move |_lua: &Lua, args: (Option<String>,)| -> LuaResult<&'static str> {
+            let Some(name) = args.0 else {
+                return Ok(clock_state.load().source);
+            };
+            let kind = SourceKind::from_name(&name).ok_or_else(|| {
+                LuaError::RuntimeError(format!("unknown clock source: {} (expected \"link\" or \"internal\")", name))
+            })?;
+            send_to_clock(&cloned_sender, ClockCommand::SetTimeSource(kind))?;
+            Ok(kind.name())
+        }
+    });
+    let _ = interpreter.register_function("add_subscriber", {
         let cloned_sender = sender_to_clock.clone();
-        let cloned_receiver = receiver_for_main.clone();
-        move |_lua: &Lua, _args: ()| -> LuaResult<i32> {
-            cloned_sender.send(clock::ClockControlMessage {
-                name: "peers".to_string(),
-                args: vec![],
-            }).unwrap();
-            let recv = cloned_receiver.lock().unwrap().recv().unwrap();
-            match recv.name.as_str() {
-                "peers" => {
-                    Ok(recv.args[0].parse::<i32>().unwrap())
-                },
-                _ => {
-                    println!("Unknown command: {}", recv.name);
-                    Ok(0)
+        move |_lua: &Lua, args: (String,)| -> LuaResult<()> {
+            send_to_clock(&cloned_sender, ClockCommand::AddStream(StreamSpec {
+                name: args.0,
+                content: StreamContent::Events(Vec::new()),
+                quantize: Quantize::Now
+            }))
+        }
+    });
+    let _ = interpreter.register_function("stream", {
+        let cloned_sender = sender_to_clock.clone();
+        move |lua: &Lua, args: (String, LuaValue, Quantize)| -> LuaResult<()> {
+            // A function generates the stream cycle by cycle:
+            // `stream("arp", function(cycle, tempo, state) ... end)`.
+            if let LuaValue::Function(function) = args.1 {
+                timers::register_generator(lua, &args.0, function)?;
+                return send_to_clock(&cloned_sender, ClockCommand::AddGenerator(args.0));
+            }
+            send_to_clock(&cloned_sender, ClockCommand::AddStream(StreamSpec {
+                name: args.0,
+                content: StreamContent::from_lua(args.1, lua)?,
+                quantize: args.2
+            }))
+        }
+    });
+    let _ = interpreter.register_function("stream_add", {
+        let cloned_sender = sender_to_clock.clone();
+        move |_lua: &Lua, args: (String, StreamContent, Quantize)| -> LuaResult<()> {
+            send_to_clock(&cloned_sender, ClockCommand::UpdateStream(StreamSpec {
+                name: args.0,
+                content: args.1,
+                quantize: args.2
+            }))
+        }
+    });
+    let _ = interpreter.register_function("pat", {
+        move |_lua: &Lua, args: (String, Option<LuaTable>)| -> LuaResult<Pattern<Event>> {
+            let notation = Notation::parse(&args.0).map_err(LuaError::external)?;
+            let (mut channel, mut velocity, mut output) = (0, 100, None);
+            if let Some(options) = args.1 {
+                if let Some(value) = options.get::<_, Option<i64>>("channel")? {
+                    channel = streams::midi_byte("channel", value, 0x0F)?;
+                }
+                if let Some(value) = options.get::<_, Option<i64>>("velocity")? {
+                    velocity = streams::midi_byte("velocity", value, 0x7F)?;
                 }
+                output = options.get::<_, Option<String>>("out")?;
             }
+            let events = streams::notation_events(&notation, channel, velocity);
+            Ok(match output {
+                Some(output) => events.fmap(move |event| event.with_output(Some(output.clone()))),
+                None => events,
+            })
         }
     });
-    let _ = interpreter.register_function("add_subscriber", {
+    let _ = interpreter.register_function("dirt", {
+        move |_lua: &Lua, args: (String, Option<HashMap<String, Param>>)| -> LuaResult<Pattern<Event>> {
+            let notation = Notation::parse(&args.0).map_err(LuaError::external)?;
+            let mut params: Vec<(String, Param)> = args.1.unwrap_or_default().into_iter().collect();
+            let output = match params.iter().position(|(key, _)| key == "out") {
+                Some(index) => match params.remove(index).1 {
+                    Param::Str(output) => Some(output),
+                    _ => return Err(LuaError::RuntimeError("out must be a string".to_string())),
+                },
+                None => None,
+            };
+            params.sort_by(|a, b| a.0.cmp(&b.0));
+            let events = streams::dirt_events(&notation, params);
+            Ok(events.fmap(move |event| event.with_output(output.clone())))
+        }
+    });
+    let _ = interpreter.register_function("stack", {
+        move |_lua: &Lua, args: Variadic<Pattern<Event>>| -> LuaResult<Pattern<Event>> {
+            Ok(Pattern::stack(args.into_iter().collect()))
+        }
+    });
+    let _ = interpreter.register_function("cat", {
+        move |_lua: &Lua, args: Variadic<Pattern<Event>>| -> LuaResult<Pattern<Event>> {
+            Ok(Pattern::slowcat(args.into_iter().collect()))
+        }
+    });
+    let _ = interpreter.register_function("fastcat", {
+        move |_lua: &Lua, args: Variadic<Pattern<Event>>| -> LuaResult<Pattern<Event>> {
+            Ok(Pattern::fastcat(args.into_iter().collect()))
+        }
+    });
+    let _ = interpreter.register_function("stream_remove", {
         let cloned_sender = sender_to_clock.clone();
-        move |_lua: &Lua, _args: (String,)| -> LuaResult<()> {
-            cloned_sender.send(clock::ClockControlMessage {
-                name: "add_subscriber".to_string(),
-                args: vec![_args.0],
-            }).unwrap();
-            Ok(())
+        move |_lua: &Lua, args: (String, Quantize)| -> LuaResult<()> {
+            send_to_clock(&cloned_sender, ClockCommand::RemoveStream(args.0, args.1))
         }
     });
     let _ = interpreter.register_function("subscribers", {
         let cloned_sender = sender_to_clock.clone();
-        let cloned_receiver = receiver_for_main.clone();
-        move |_lua: &Lua, _args: ()| -> LuaResult<i32> {
-            cloned_sender.send(clock::ClockControlMessage {
-                name: "subscribers".to_string(),
-                args: vec![],
-            }).unwrap();
-            let recv = cloned_receiver.lock().unwrap().recv().unwrap();
-            match recv.name.as_str() {
-                "subscribers" => {
-                    Ok(recv.args[0].parse::<i32>().unwrap())
-                },
-                _ => {
-                    println!("Unknown command: {}", recv.name);
-                    Ok(0)
-                }
+        move |_lua: &Lua, _args: ()| -> LuaResult<usize> {
+            match query_clock(&cloned_sender, ClockCommand::GetSubscribers)? {
+                ClockReply::Subscribers(count) => Ok(count),
+                reply => Err(unexpected_reply(reply)),
             }
         }
     });
     let _ = interpreter.register_function("test", {
         let cloned_sender = sender_to_clock.clone();
         move |_lua: &Lua, _args: ()| -> LuaResult<()> {
-            cloned_sender.send(clock::ClockControlMessage {
-                name: "test".to_string(),
-                args: vec![],
-            }).unwrap();
+            send_to_clock(&cloned_sender, ClockCommand::Test)
+        }
+    });
+    let _ = interpreter.register_function("set_latency", {
+        let cloned_dispatcher = dispatcher.clone();
+        move |_lua: &Lua, args: (String, f64)| -> LuaResult<()> {
+            let output = Output::from_name(&args.0).ok_or_else(|| {
+                LuaError::RuntimeError(format!("unknown output: {} (expected \"midi\" or \"osc\")", args.0))
+            })?;
+            cloned_dispatcher.send(DispatcherMessage::SetLatency(output, (args.1 * 1000.0) as i64))
+                .map_err(|_| LuaError::RuntimeError("the dispatcher is not running".to_string()))
+        }
+    });
+    let _ = interpreter.register_function("midi_clock", {
+        let cloned_sender = sender_to_clock.clone();
+        move |_lua: &Lua, args: (bool,)| -> LuaResult<()> {
+            send_to_clock(&cloned_sender, ClockCommand::SetMidiClock(args.0))
+        }
+    });
+    let _ = interpreter.register_function("follow_midi_clock", {
+        let cloned_sender = sender_to_clock.clone();
+        move |_lua: &Lua, args: (bool,)| -> LuaResult<()> {
+            send_to_clock(&cloned_sender, ClockCommand::FollowMidiClock(args.0))
+        }
+    });
+    let _ = interpreter.register_function("midi_inputs", {
+        move |_lua: &Lua, _args: ()| -> LuaResult<Vec<String>> {
+            midi_input::input_ports().map_err(|err| LuaError::RuntimeError(err.to_string()))
+        }
+    });
+    let _ = interpreter.register_function("on_note", {
+        move |lua: &Lua, args: (Option<LuaFunction>,)| -> LuaResult<()> {
+            lua.set_named_registry_value(midi_input::ON_NOTE, args.0)
+        }
+    });
+    let _ = interpreter.register_function("on_cc", {
+        move |lua: &Lua, args: (Option<LuaFunction>,)| -> LuaResult<()> {
+            lua.set_named_registry_value(midi_input::ON_CC, args.0)
+        }
+    });
+    let _ = interpreter.register_function("midi_cc", {
+        let controls = midi_inputs.controls();
+        move |_lua: &Lua, args: (u8, Option<u8>)| -> LuaResult<Option<u8>> {
+            let channel = args.1.unwrap_or(0);
+            Ok(controls.lock().unwrap().get(&(channel, args.0)).copied())
+        }
+    });
+    let _ = interpreter.register_function("midi_ports", {
+        move |_lua: &Lua, _args: ()| -> LuaResult<Vec<String>> {
+            midi::output_ports().map_err(|err| LuaError::RuntimeError(err.to_string()))
+        }
+    });
+    let _ = interpreter.register_function("midi_outputs", {
+        let cloned_midi = midi.clone();
+        move |_lua: &Lua, _args: ()| -> LuaResult<HashMap<String, String>> {
+            Ok(cloned_midi.lock().unwrap().aliases().into_iter().collect())
+        }
+    });
+    let _ = interpreter.register_function("midi_open", {
+        let cloned_midi = midi.clone();
+        move |_lua: &Lua, args: (String, String)| -> LuaResult<()> {
+            MidiOutputs::open_shared(&cloned_midi, &args.0, &args.1)
+                .map_err(|err| LuaError::RuntimeError(err.to_string()))
+        }
+    });
+    let _ = interpreter.register_function("midi_virtual", {
+        let cloned_midi = midi.clone();
+        move |_lua: &Lua, args: (String, Option<String>)| -> LuaResult<()> {
+            let name = args.1.unwrap_or_else(|| format!("Eremit {}", args.0));
+            MidiOutputs::open_virtual_shared(&cloned_midi, &args.0, &name)
+                .map_err(|err| LuaError::RuntimeError(err.to_string()))
+        }
+    });
+    let _ = interpreter.register_function("midi_close", {
+        let cloned_midi = midi.clone();
+        move |_lua: &Lua, args: (String,)| -> LuaResult<()> {
+            cloned_midi.lock().unwrap().close(&args.0)
+                .map_err(|err| LuaError::RuntimeError(err.to_string()))
+        }
+    });
+    let _ = interpreter.register_function("midi_reconnect", {
+        let cloned_midi = midi.clone();
+        move |_lua: &Lua, args: (Option<String>,)| -> LuaResult<()> {
+            let alias = args.0.unwrap_or_else(|| midi::DEFAULT_OUTPUT.to_string());
+            MidiOutputs::reconnect_shared(&cloned_midi, &alias)
+                .map_err(|err| LuaError::RuntimeError(err.to_string()))
+        }
+    });
+    let _ = interpreter.register_function("osc_target", {
+        let cloned_targets = targets.clone();
+        move |_lua: &Lua, args: (String, String)| -> LuaResult<()> {
+            osc::OscTargets::set_target_shared(&cloned_targets, &args.0, &args.1)
+                .map_err(|err| LuaError::RuntimeError(err.to_string()))
+        }
+    });
+    let _ = interpreter.register_function("osc_targets", {
+        let cloned_targets = targets.clone();
+        move |_lua: &Lua, _args: ()| -> LuaResult<HashMap<String, String>> {
+            Ok(cloned_targets.lock().unwrap().targets().into_iter().collect())
+        }
+    });
+    let _ = interpreter.register_function("osc_remove", {
+        let cloned_targets = targets.clone();
+        move |_lua: &Lua, args: (String,)| -> LuaResult<()> {
+            cloned_targets.lock().unwrap().remove_target(&args.0)
+                .map_err(|err| LuaError::RuntimeError(err.to_string()))
+        }
+    });
+    let _ = interpreter.register_function("osc_send", {
+        let cloned_targets = targets.clone();
+        move |_lua: &Lua, args: (Option<String>, String, Variadic<LuaValue>)| -> LuaResult<()> {
+            let message = OscMessage {
+                addr: args.1,
+                args: args.2.into_iter().map(osc::lua_argument).collect::<LuaResult<_>>()?,
+            };
+            cloned_targets.lock().unwrap().send(args.0.as_deref(), message, None)
+                .map_err(|err| LuaError::RuntimeError(err.to_string()))
+        }
+    });
+    let callback_ids = Arc::new(AtomicU64::new(0));
+    let next_id = move || callback_ids.fetch_add(1, Ordering::Relaxed);
+    let register_timer = {
+        let cloned_sender = sender_to_clock.clone();
+        let next_id = next_id.clone();
+        move |lua: &Lua, period: Period, function: LuaFunction| -> LuaResult<u64> {
+            let id = next_id();
+            timers::register(lua, id, function)?;
+            send_to_clock(&cloned_sender, ClockCommand::AddTimer(id, period))?;
+            Ok(id)
+        }
+    };
+    let _ = interpreter.register_function("on_beat", {
+        let register_timer = register_timer.clone();
+        move |lua: &Lua, args: (LuaFunction,)| -> LuaResult<u64> {
+            register_timer(lua, Period::Beats(1.into()), args.0)
+        }
+    });
+    let _ = interpreter.register_function("on_bar", {
+        let register_timer = register_timer.clone();
+        move |lua: &Lua, args: (LuaFunction,)| -> LuaResult<u64> {
+            register_timer(lua, Period::Bar, args.0)
+        }
+    });
+    let _ = interpreter.register_function("every", {
+        move |lua: &Lua, args: (Period, LuaFunction)| -> LuaResult<u64> {
+            register_timer(lua, args.0, args.1)
+        }
+    });
+    let _ = interpreter.register_function("cancel", {
+        let cloned_sender = sender_to_clock.clone();
+        move |lua: &Lua, args: (u64,)| -> LuaResult<()> {
+            timers::unregister(lua, args.0)?;
+            routines::cancel(lua, args.0)?;
+            send_to_clock(&cloned_sender, ClockCommand::RemoveTimer(args.0))
+        }
+    });
+    let _ = interpreter.register_function("spawn", {
+        let cloned_sender = sender_to_clock.clone();
+        move |lua: &Lua, args: (LuaFunction,)| -> LuaResult<u64> {
+            let id = next_id();
+            routines::spawn(lua, id, args.0)?;
+            send_to_clock(&cloned_sender, ClockCommand::Spawn(id))?;
+            Ok(id)
+        }
+    });
+    if let Err(err) = routines::install(&interpreter.lua) {
+        println!("Lua error: {}", err);
+    }
+    let _ = interpreter.register_function("panic", {
+        let cloned_dispatcher = dispatcher.clone();
+        move |_lua: &Lua, _args: ()| -> LuaResult<()> {
+            let _ = cloned_dispatcher.send(DispatcherMessage::Clear);
+            let _ = cloned_dispatcher.send(DispatcherMessage::ReleaseNotes);
             Ok(())
         }
     });
     // This is a test event that should repeat every bar
     // let _ = interpreter.run();
-    let _ = interpreter.run();
-    println!("{}", ascii::GOODBYE);
+    let _ = match rendering {
+        true => interpreter.serve(),
+        false => interpreter.run(),
+    };
+    // Nothing may keep sounding once we are gone: the clock stops scheduling
+    // and has the dispatcher release the notes, which we wait for.
+    let _ = sender_to_clock.send(ClockCommand::Exit);
+    let _ = clock_thread.join();
+    let (reply_sender, reply_receiver) = mpsc::channel::<()>();
+    if dispatcher.send(DispatcherMessage::Ack(reply_sender)).is_ok() {
+        let _ = reply_receiver.recv();
+    }
+    if !rendering {
+        println!("{}", ascii::GOODBYE);
+    }
     Ok(())
 }
\ No newline at end of file
@@ -1,169 +1,393 @@
 use midir::{MidiOutput, MidiOutputPort, MidiOutputConnection};
-use std::io::{stdin, stdout, Write};
+use std::collections::HashMap;
 use std::error::Error;
 use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-pub fn _setup_midi(port: String) -> StdResult<MidiOutputConnection, Box<dyn Error>> {
-    let midi_out = MidiOutput::new("My Test Output")?;
-    // Get an output port (read from console if multiple are available)
-    let out_ports = midi_out.ports();
-
-    // Check if port is in the list of out_ports
-    if port != "" {
-        for (i, p) in out_ports.iter().enumerate() {
-            if midi_out.port_name(p).unwrap() == port {
-                println!("Choosing the config selected port: {}", midi_out.port_name(p).unwrap());
-                return Ok(midi_out.connect(p, "midir-test")?);
+pub mod codec;
+pub mod clock_output;
+pub mod clock_input;
+pub mod input;
+pub use codec::{MidiMessage, PITCH_BEND_CENTER};
+
+/// The names of the MIDI output ports available.
+pub fn output_ports() -> StdResult<Vec<String>, Box<dyn Error>> {
+    let midi_out = MidiOutput::new("Eremit output")?;
+    Ok(port_names(&midi_out))
+}
+
+fn port_names(midi_out: &MidiOutput) -> Vec<String> {
+    midi_out.ports().iter().filter_map(|p| midi_out.port_name(p).ok()).collect()
+}
+
+/// The port called `port` among `ports`, or failing that the first one whose
+/// name contains it, ignoring case: `volca` finds `volca beats:volca beats MIDI 1 20:0`.
+pub fn find_port<'a>(ports: &'a [String], port: &str) -> Option<&'a String> {
+    let pattern = port.to_lowercase();
+    ports.iter().find(|name| *name == port)
+        .or_else(|| ports.iter().find(|name| name.to_lowercase().contains(&pattern)))
+}
+
+/// Connect to an output port, see `find_port`.
+pub fn open_output(port: &str) -> StdResult<(MidiOutputConnection, String), Box<dyn Error>> {
+    let midi_out = MidiOutput::new("Eremit output")?;
+    let ports: Vec<(MidiOutputPort, String)> = midi_out.ports().into_iter()
+        .filter_map(|p| midi_out.port_name(&p).ok().map(|name| (p, name)))
+        .collect();
+    let names: Vec<String> = ports.iter().map(|(_, name)| name.clone()).collect();
+    let name = find_port(&names, port)
+        .ok_or_else(|| format!("MIDI output port not found: {}", port))?;
+    let (found, name) = ports.iter().find(|(_, other)| other == name).unwrap();
+    let connection = midi_out.connect(found, "eremit-out")?;
+    Ok((connection, name.clone()))
+}
+
+/// Create a virtual output port other applications can connect to.
+#[cfg(unix)]
+pub fn open_virtual(name: &str) -> StdResult<MidiOutputConnection, Box<dyn Error>> {
+    use midir::os::unix::VirtualOutput;
+    let midi_out = MidiOutput::new("Eremit output")?;
+    Ok(midi_out.create_virtual(name)?)
+}
+
+#[cfg(not(unix))]
+pub fn open_virtual(_name: &str) -> StdResult<MidiOutputConnection, Box<dyn Error>> {
+    Err("virtual MIDI ports are not supported on this platform".into())
+}
+
+/// Notes currently sounding, keyed by channel and note. Notes triggered
+/// several times are counted so that overlapping events are tracked too.
+#[derive(Debug, Default)]
+pub struct HeldNotes {
+    counts: HashMap<(u8, u8), u32>,
+}
+
+impl HeldNotes {
+    pub fn press(&mut self, channel: u8, note: u8) {
+        *self.counts.entry((channel, note)).or_insert(0) += 1;
+    }
+
+    pub fn release(&mut self, channel: u8, note: u8) {
+        if let Some(count) = self.counts.get_mut(&(channel, note)) {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&(channel, note));
             }
         }
-        return Err("port not found".into());
     }
 
-    let out_port: &MidiOutputPort = match out_ports.len() {
-        0 => return Err("no output port found".into()),
-        1 => {
-            println!(
-                "Choosing the only available output port: {}",
-                midi_out.port_name(&out_ports[0]).unwrap()
-            );
-            &out_ports[0]
+    /// Forget every held note, returning them as `(channel, note)` pairs.
+    pub fn drain(&mut self) -> Vec<(u8, u8)> {
+        self.counts.drain().map(|(key, _)| key).collect()
+    }
+}
+
+pub struct MidiConnexion {
+    conn_out: MidiOutputConnection,
+    /// Name of the port, used to reconnect.
+    port: String,
+    /// Whether the port was created by us rather than found.
+    virtual_port: bool,
+    held: HeldNotes,
+    /// Also send All Notes Off (CC 123) on every channel when releasing notes.
+    all_notes_off: bool,
+}
+
+impl MidiConnexion {
+    /// Connect to a port, see `find_port`.
+    pub fn open(port: &str) -> StdResult<Self, Box<dyn Error>> {
+        let (conn_out, port) = open_output(port)?;
+        Ok(MidiConnexion {
+            conn_out,
+            port,
+            virtual_port: false,
+            held: HeldNotes::default(),
+            all_notes_off: false,
+        })
+    }
+
+    pub fn create_virtual(name: &str) -> StdResult<Self, Box<dyn Error>> {
+        Ok(MidiConnexion {
+            conn_out: open_virtual(name)?,
+            port: name.to_string(),
+            virtual_port: true,
+            held: HeldNotes::default(),
+            all_notes_off: false,
+        })
+    }
+
+    pub fn port(&self) -> &str {
+        &self.port
+    }
+
+    pub fn set_all_notes_off(&mut self, enabled: bool) {
+        self.all_notes_off = enabled;
+    }
+
+    /// Send a NoteOff for every note still sounding, so that nothing hangs
+    /// on the receiving end.
+    pub fn release_notes(&mut self) -> Result<(), Box<dyn Error>> {
+        for (channel, note) in self.held.drain() {
+            self.conn_out.send(&MidiMessage::NoteOff(note, 0, channel).encode())?;
         }
-        _ => {
-            println!("\nAvailable output ports:");
-            for (i, p) in out_ports.iter().enumerate() {
-                println!("{}: {}", i, midi_out.port_name(p).unwrap());
+        if self.all_notes_off {
+            for channel in 0..16 {
+                self.conn_out.send(&MidiMessage::ControlChange(123, 0, channel).encode())?;
             }
-            print!("Please select output port: ");
-            stdout().flush()?;
-            let mut input = String::new();
-            stdin().read_line(&mut input)?;
-            out_ports
-                .get(input.trim().parse::<usize>()?)
-                .ok_or("invalid output port selected")?
         }
-    };
-    println!("\nOpening connection");
-    let conn_out = midi_out.connect(out_port, "midir-test")?;
-    // Return the conn_out
-  Ok(conn_out)
-}
+        Ok(())
+    }
 
-pub fn setup_midi_connection(port: String) -> MidiOutputConnection {
-    loop {
-        match _setup_midi(port.clone()) {
-            Ok(connection) => return connection,
-            Err(err) => {
-                println!("Error: {}", err);
-            }
+    pub fn send(&mut self, message: MidiMessage) -> Result<(), Box<dyn Error>> {
+        self.conn_out.send(&message.encode())?;
+        match message {
+            // A NoteOn with a null velocity is a NoteOff.
+            MidiMessage::NoteOn(note, 0, channel) | MidiMessage::NoteOff(note, _, channel) => {
+                self.held.release(channel & 0x0F, note & 0x7F)
+            },
+            MidiMessage::NoteOn(note, _, channel) => self.held.press(channel & 0x0F, note & 0x7F),
+            _ => {}
         }
+        Ok(())
     }
 }
 
+/// Where a MIDI message goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MidiTarget {
+    Default,
+    Alias(String),
+    /// Every open output, for clock and transport messages.
+    All,
+}
 
-pub enum MidiMessage {
-    NoteOn(u8, u8, u8),
-    NoteOff(u8, u8),
-    ControlChange(u8, u8, u8),
-    ProgramChange(u8, u8),
-    PitchBend(u8, u8),
-    Aftertouch(u8, u8, u8),
-    Sysex(Vec<u8>),
-    MidiClock,
-    MidiStart,
-    MidiContinue,
-    MidiStop,
-    Reset,
+impl MidiTarget {
+    pub fn from_alias(alias: Option<&str>) -> Self {
+        match alias {
+            Some(alias) => MidiTarget::Alias(alias.to_string()),
+            None => MidiTarget::Default,
+        }
+    }
 }
 
-pub struct MidiConnexion {
-    conn_out: MidiOutputConnection,
+/// Alias of the output chosen at startup.
+pub const DEFAULT_OUTPUT: &str = "default";
+
+/// How often missing ports are looked for.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// MIDI outputs opened at the same time, addressed by alias. Outputs whose
+/// port is missing are remembered and connected as soon as it shows up.
+#[derive(Default)]
+pub struct MidiOutputs {
+    outputs: HashMap<String, MidiConnexion>,
+    /// The port wanted for every alias opened by name, connected or not.
+    wanted: HashMap<String, String>,
+    all_notes_off: bool,
 }
 
-impl MidiConnexion {
-    pub fn new(port: String) -> Self {
-        MidiConnexion {
-            conn_out: setup_midi_connection(port),
-        }
+impl MidiOutputs {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    pub fn send(&mut self, message_type: MidiMessage) -> Result<(), Box<dyn Error>> {
-        let _ = match message_type {
-            MidiMessage::NoteOn(note, velocity, channel) => self.note_on(note, velocity, channel),
-            MidiMessage::NoteOff(note, channel) => self.note_off(note, channel),
-            MidiMessage::ControlChange(control, value, channel) => self.control_change(control, value, channel),
-            MidiMessage::MidiClock => self.midi_clock(),
-            MidiMessage::MidiStart => self.midi_clock_start(),
-            MidiMessage::MidiContinue => self.midi_clock_continue(),
-            MidiMessage::MidiStop => self.midi_clock_stop(),
-            MidiMessage::ProgramChange(program, channel) => self.program_change(program, channel),
-            MidiMessage::PitchBend(pitch, channel) => self.pitch_bend(pitch, channel),
-            MidiMessage::Aftertouch(note, value, channel) => self.aftertouch(note, value, channel),
-            MidiMessage::Reset => self.reset(),
-            MidiMessage::Sysex(message) => self.send_sysex(message)
-        };
+    pub fn set_all_notes_off(&mut self, enabled: bool) {
+        self.all_notes_off = enabled;
+        for output in self.outputs.values_mut() {
+            output.set_all_notes_off(enabled);
+        }
+    }
 
-        Ok(())
+    /// Register a connection under `alias`, replacing (and silencing) the
+    /// output that had the same alias.
+    pub fn insert(&mut self, alias: &str, mut connexion: MidiConnexion) {
+        connexion.set_all_notes_off(self.all_notes_off);
+        println!("MIDI output {}: {}", alias, connexion.port());
+        if let Some(mut previous) = self.outputs.insert(alias.to_string(), connexion) {
+            let _ = previous.release_notes();
+        }
     }
 
-    fn send_sysex(&mut self, message: Vec<u8>) -> Result<(), Box<dyn Error>> {
-        self.conn_out.send(&message)?;
+    /// Open `port` under `alias`. When the port cannot be found, it keeps
+    /// being looked for in the background.
+    pub fn open(&mut self, alias: &str, port: &str) -> Result<(), Box<dyn Error>> {
+        self.wanted.insert(alias.to_string(), port.to_string());
+        let connexion = MidiConnexion::open(port)?;
+        self.insert(alias, connexion);
         Ok(())
     }
 
-    fn note_on(&mut self, note: u8, velocity: u8, channel: u8) -> Result<(), Box<dyn Error>> {
-        self.conn_out.send(&[0x90 | channel, note, velocity])?;
+    /// Create a virtual port called `name` under `alias`.
+    pub fn open_virtual(&mut self, alias: &str, name: &str) -> Result<(), Box<dyn Error>> {
+        let connexion = MidiConnexion::create_virtual(name)?;
+        self.wanted.remove(alias);
+        self.insert(alias, connexion);
         Ok(())
     }
 
-    fn note_off(&mut self, note: u8, channel: u8) -> Result<(), Box<dyn Error>> {
-        self.conn_out.send(&[0x80 | channel, note, 0])?;
-        Ok(())
+    /// Open the default output: the configured port if any, the first port
+    /// available otherwise. Never blocks, running without MIDI at worst.
+    pub fn open_default(&mut self, port: &str) {
+        let port = match port {
+            "" => match output_ports().ok().and_then(|ports| ports.into_iter().next()) {
+                Some(port) => port,
+                None => {
+                    println!("No MIDI output port found, running without MIDI");
+                    return;
+                }
+            },
+            port => port.to_string(),
+        };
+        if let Err(err) = self.open(DEFAULT_OUTPUT, &port) {
+            println!("MIDI output error ({}): {}, waiting for it to appear", DEFAULT_OUTPUT, err);
+        }
     }
 
-    fn control_change(&mut self, control: u8, value: u8, channel: u8) -> Result<(), Box<dyn Error>> {
-        self.conn_out.send(&[0xB0 | channel, control, value])?;
-        Ok(())
+    pub fn close(&mut self, alias: &str) -> Result<(), Box<dyn Error>> {
+        let wanted = self.wanted.remove(alias);
+        match self.outputs.remove(alias) {
+            Some(mut output) => output.release_notes(),
+            None if wanted.is_some() => Ok(()),
+            None => Err(format!("no MIDI output called {}", alias).into()),
+        }
     }
 
-    fn program_change(&mut self, program: u8, channel: u8) -> Result<(), Box<dyn Error>> {
-        self.conn_out.send(&[0xC0 | channel, program])?;
+    /// Register what `connect` returns under `alias`. Connecting goes
+    /// through the system MIDI API, so `outputs`, which the dispatcher locks
+    /// for every message, is only locked to insert the connection.
+    fn connect_shared(
+        outputs: &Mutex<Self>,
+        alias: &str,
+        connect: impl FnOnce() -> Result<MidiConnexion, Box<dyn Error>>
+    ) -> Result<(), Box<dyn Error>> {
+        let connexion = connect()?;
+        outputs.lock().unwrap().insert(alias, connexion);
         Ok(())
     }
 
-    fn pitch_bend(&mut self, value: u8, channel: u8) -> Result<(), Box<dyn Error>> {
-        self.conn_out.send(&[0xE0 | channel, value])?;
-        Ok(())
+    /// `open`, for outputs shared with the dispatcher.
+    pub fn open_shared(outputs: &Mutex<Self>, alias: &str, port: &str) -> Result<(), Box<dyn Error>> {
+        outputs.lock().unwrap().wanted.insert(alias.to_string(), port.to_string());
+        Self::connect_shared(outputs, alias, || MidiConnexion::open(port))
     }
 
-    fn aftertouch(&mut self, note: u8, value: u8, channel: u8) -> Result<(), Box<dyn Error>> {
-        self.conn_out.send(&[0xA0 | channel, note, value])?;
+    /// `open_virtual`, for outputs shared with the dispatcher.
+    pub fn open_virtual_shared(outputs: &Mutex<Self>, alias: &str, name: &str) -> Result<(), Box<dyn Error>> {
+        Self::connect_shared(outputs, alias, || MidiConnexion::create_virtual(name))?;
+        outputs.lock().unwrap().wanted.remove(alias);
         Ok(())
     }
 
+    /// Open the port of `alias` again, after the device was unplugged for
+    /// instance. The previous connection is silenced once replaced.
+    pub fn reconnect_shared(outputs: &Mutex<Self>, alias: &str) -> Result<(), Box<dyn Error>> {
+        let (port, virtual_port) = {
+            let outputs = outputs.lock().unwrap();
+            match outputs.outputs.get(alias) {
+                Some(output) => (output.port.clone(), output.virtual_port),
+                None => match outputs.wanted.get(alias) {
+                    Some(port) => (port.clone(), false),
+                    None => return Err(format!("no MIDI output called {}", alias).into()),
+                },
+            }
+        };
+        Self::connect_shared(outputs, alias, || match virtual_port {
+            true => MidiConnexion::create_virtual(&port),
+            false => MidiConnexion::open(&port),
+        })
+    }
 
-    fn midi_clock(&mut self) -> Result<(), Box<dyn Error>> {
-        self.conn_out.send(&[0xF8])?;
-        Ok(())
+    /// The aliases in use with the port they are connected to, or wait for.
+    pub fn aliases(&self) -> Vec<(String, String)> {
+        let mut aliases: Vec<(String, String)> = self.outputs.iter()
+            .map(|(alias, output)| (alias.clone(), output.port().to_string()))
+            .chain(self.wanted.iter()
+                .filter(|(alias, _)| !self.outputs.contains_key(*alias))
+                .map(|(alias, port)| (alias.clone(), format!("{} (missing)", port))))
+            .collect();
+        aliases.sort();
+        aliases
     }
 
-    fn midi_clock_start(&mut self) -> Result<(), Box<dyn Error>> {
-        self.conn_out.send(&[0xFA])?;
-        Ok(())
+    /// Drop the outputs whose port disappeared, `available` being the ports
+    /// currently present, and return the aliases whose port is back with
+    /// the port to connect them to.
+    pub fn refresh(&mut self, available: &[String]) -> Vec<(String, String)> {
+        let gone: Vec<String> = self.outputs.iter()
+            .filter(|(_, output)| !output.virtual_port && !available.contains(&output.port))
+            .map(|(alias, _)| alias.clone())
+            .collect();
+        for alias in gone {
+            println!("MIDI output {} disconnected", alias);
+            self.outputs.remove(&alias);
+        }
+        self.wanted.iter()
+            .filter(|(alias, port)| !self.outputs.contains_key(*alias) && find_port(available, port).is_some())
+            .map(|(alias, port)| (alias.clone(), port.clone()))
+            .collect()
     }
 
-    fn midi_clock_stop(&mut self) -> Result<(), Box<dyn Error>> {
-        self.conn_out.send(&[0xFC])?;
-        Ok(())
+    /// Look for missing ports in the background. Ports are listed and
+    /// connected with `outputs` unlocked, the dispatcher locking it for every
+    /// message, and only swapped in under the lock.
+    pub fn watch(outputs: Arc<Mutex<MidiOutputs>>) {
+        thread::spawn(move || {
+            let Ok(midi_out) = MidiOutput::new("Eremit watcher") else {
+                println!("MIDI error: cannot watch for output ports");
+                return;
+            };
+            loop {
+                thread::sleep(WATCH_INTERVAL);
+                let available = port_names(&midi_out);
+                let back = outputs.lock().unwrap().refresh(&available);
+                for (alias, port) in back {
+                    let connexion = match MidiConnexion::open(&port) {
+                        Ok(connexion) => connexion,
+                        Err(err) => {
+                            println!("MIDI output error ({}): {}", alias, err);
+                            continue;
+                        }
+                    };
+                    let mut outputs = outputs.lock().unwrap();
+                    // Unless it was closed or opened again meanwhile.
+                    if outputs.wanted.get(&alias) == Some(&port) && !outputs.outputs.contains_key(&alias) {
+                        outputs.insert(&alias, connexion);
+                    }
+                }
+            }
+        });
     }
 
-    fn midi_clock_continue(&mut self) -> Result<(), Box<dyn Error>> {
-        self.conn_out.send(&[0xFB])?;
-        Ok(())
+    /// Messages for an output waiting for its port are dropped.
+    pub fn send(&mut self, target: &MidiTarget, message: MidiMessage) -> Result<(), Box<dyn Error>> {
+        let alias = match target {
+            MidiTarget::Default => DEFAULT_OUTPUT,
+            MidiTarget::Alias(alias) => alias,
+            MidiTarget::All => return self.each(|output| output.send(message.clone())),
+        };
+        match self.outputs.get_mut(alias) {
+            Some(output) => output.send(message),
+            None if alias == DEFAULT_OUTPUT || self.wanted.contains_key(alias) => Ok(()),
+            None => Err(format!("no MIDI output called {}", alias).into()),
+        }
     }
 
-    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
-        self.conn_out.send(&[0xFF])?;
-        Ok(())
+    /// Release the held notes of every output.
+    pub fn release_notes(&mut self) -> Result<(), Box<dyn Error>> {
+        self.each(MidiConnexion::release_notes)
     }
 
-}
\ No newline at end of file
+    /// Call `f` on every output, a broken one not keeping the others from
+    /// their messages. The errors are reported together.
+    fn each(&mut self, mut f: impl FnMut(&mut MidiConnexion) -> Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+        let errors: Vec<String> = self.outputs.iter_mut()
+            .filter_map(|(alias, output)| f(output).err().map(|err| format!("{}: {}", alias, err)))
+            .collect();
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join(", ").into()),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::VecDeque;
+
+use crate::midi::clock_output::PPQN;
+
+/// Pulses over which the tempo is averaged, a bar of 4/4.
+const WINDOW: usize = 4 * PPQN as usize;
+
+/// Pulses further apart than this (in microseconds, a tempo under 2.5 BPM)
+/// mean the clock was interrupted.
+const MAX_INTERVAL: u64 = 1_000_000;
+
+/// Tempo estimation from incoming MIDI clock pulses, averaged over a sliding
+/// window to smooth the jitter of the sender and of the MIDI driver.
+#[derive(Debug, Default)]
+pub struct ClockInput {
+    last: Option<u64>,
+    intervals: VecDeque<u64>,
+}
+
+impl ClockInput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a pulse received at `stamp` (in microseconds) and return the
+    /// tempo, once a beat worth of pulses has been received.
+    pub fn pulse(&mut self, stamp: u64) -> Option<f64> {
+        if let Some(last) = self.last.replace(stamp) {
+            let interval = stamp.saturating_sub(last);
+            if interval == 0 || interval > MAX_INTERVAL {
+                self.intervals.clear();
+                return None;
+            }
+            self.intervals.push_back(interval);
+            if self.intervals.len() > WINDOW {
+                self.intervals.pop_front();
+            }
+        }
+        if self.intervals.len() < PPQN as usize {
+            return None;
+        }
+        let mean = self.intervals.iter().sum::<u64>() as f64 / self.intervals.len() as f64;
+        Some(60_000_000.0 / (mean * PPQN as f64))
+    }
+
+    /// Forget past pulses, the next ones starting a new estimation.
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.intervals.clear();
+    }
+}
@@ -0,0 +1,116 @@
+use num::Zero;
+
+use crate::midi::MidiMessage;
+use crate::streams::Time;
+
+/// MIDI clock pulses per quarter note.
+pub const PPQN: i64 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Stopped,
+    /// Running, the next window being expected to start at that beat.
+    Running(Time),
+}
+
+/// MIDI clock generator following the beat windows of the scheduler.
+/// Receivers are started on beat 0, or told where to resume with a Song
+/// Position Pointer and a Continue on the next sixteenth note when joining
+/// a session already playing or when the Link timeline jumps.
+#[derive(Debug)]
+pub struct ClockOutput {
+    state: State,
+}
+
+impl ClockOutput {
+    pub fn new() -> Self {
+        Self { state: State::Stopped }
+    }
+
+    /// The messages falling in the beat window `[begin, end)`, with their beat.
+    pub fn messages(&mut self, begin: Time, end: Time) -> Vec<(Time, MidiMessage)> {
+        let mut messages = Vec::new();
+        if let State::Running(position) = self.state {
+            if position != begin {
+                messages.push((begin, MidiMessage::MidiStop));
+                self.state = State::Stopped;
+            }
+        }
+        let from = match self.state {
+            State::Running(_) => begin,
+            State::Stopped => {
+                // The first sixteenth of the window, beat 0 during a count-in.
+                let sixteenth = Time::new(1, 4);
+                let start = ((begin / sixteenth).ceil() * sixteenth).max(Time::zero());
+                if start >= end {
+                    return messages;
+                }
+                if start.is_zero() {
+                    messages.push((start, MidiMessage::MidiStart));
+                } else {
+                    let position = (start / sixteenth).to_integer().min(0x3FFF) as u16;
+                    messages.push((start, MidiMessage::SongPosition(position)));
+                    messages.push((start, MidiMessage::MidiContinue));
+                }
+                start
+            }
+        };
+        self.state = State::Running(end);
+        let pulse = Time::new(1, PPQN.into());
+        let mut beat = (from / pulse).ceil() * pulse;
+        while beat < end {
+            messages.push((beat, MidiMessage::MidiClock));
+            beat += pulse;
+        }
+        messages
+    }
+
+    /// Stop the generator, returning whether receivers must be sent a Stop.
+    pub fn stop(&mut self) -> bool {
+        let running = self.state != State::Stopped;
+        self.state = State::Stopped;
+        running
+    }
+}
+
+impl Default for ClockOutput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(numer: i128, denom: i128) -> Time {
+        Time::new(numer, denom)
+    }
+
+    #[test]
+    fn starts_on_beat_zero() {
+        let mut clock = ClockOutput::new();
+        // A count-in: nothing before beat 0.
+        assert!(clock.messages(t(-1, 1), t(-1, 2)).is_empty());
+        let messages = clock.messages(t(-1, 2), t(1, 12));
+        assert_eq!(messages, vec![
+            (t(0, 1), MidiMessage::MidiStart),
+            (t(0, 1), MidiMessage::MidiClock),
+            (t(1, 24), MidiMessage::MidiClock),
+        ]);
+        assert_eq!(clock.messages(t(1, 12), t(1, 8)), vec![(t(1, 12), MidiMessage::MidiClock)]);
+    }
+
+    #[test]
+    fn joins_on_the_next_sixteenth() {
+        let mut clock = ClockOutput::new();
+        let messages = clock.messages(t(1, 10), t(3, 10));
+        assert_eq!(messages, vec![
+            (t(1, 4), MidiMessage::SongPosition(1)),
+            (t(1, 4), MidiMessage::MidiContinue),
+            (t(1, 4), MidiMessage::MidiClock),
+            (t(7, 24), MidiMessage::MidiClock),
+        ]);
+        assert!(messages.iter().all(|(beat, _)| *beat >= t(1, 10)));
+    }
+}
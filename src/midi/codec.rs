@@ -0,0 +1,298 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// A MIDI 1.0 message. Channels go from 0 to 15, data bytes from 0 to 127
+/// and are masked when encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MidiMessage {
+    // Channel voice
+    NoteOn(u8, u8, u8),
+    /// Note, release velocity, channel.
+    NoteOff(u8, u8, u8),
+    /// Note, pressure, channel.
+    PolyPressure(u8, u8, u8),
+    ControlChange(u8, u8, u8),
+    ProgramChange(u8, u8),
+    /// Pressure, channel.
+    ChannelPressure(u8, u8),
+    /// 14-bit value, 8192 being the center, and channel.
+    PitchBend(u16, u8),
+    // System common
+    /// Payload of a system exclusive message, without the framing bytes.
+    Sysex(Vec<u8>),
+    /// MTC quarter frame: piece (0 to 7) and its 4-bit value.
+    MtcQuarterFrame(u8, u8),
+    /// Song position, in sixteenth notes.
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+    // System realtime
+    MidiClock,
+    MidiStart,
+    MidiContinue,
+    MidiStop,
+    ActiveSensing,
+    Reset,
+}
+
+/// Center of the pitch bend range.
+pub const PITCH_BEND_CENTER: u16 = 8192;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    Empty,
+    /// The first byte is not a status byte, or an undefined one.
+    InvalidStatus(u8),
+    /// The message is shorter than its status requires.
+    Truncated(u8),
+    /// A data byte has its high bit set.
+    InvalidData(u8),
+    /// A system exclusive message without its end byte.
+    UnterminatedSysex,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "empty MIDI message"),
+            DecodeError::InvalidStatus(byte) => write!(f, "invalid MIDI status byte {:#04x}", byte),
+            DecodeError::Truncated(status) => write!(f, "truncated MIDI message (status {:#04x})", status),
+            DecodeError::InvalidData(byte) => write!(f, "invalid MIDI data byte {:#04x}", byte),
+            DecodeError::UnterminatedSysex => write!(f, "system exclusive message without end byte"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+fn lsb(value: u16) -> u8 {
+    (value & 0x7F) as u8
+}
+
+fn msb(value: u16) -> u8 {
+    ((value >> 7) & 0x7F) as u8
+}
+
+impl MidiMessage {
+    /// The bytes of the message, as sent on the wire.
+    pub fn encode(&self) -> Vec<u8> {
+        let status = |kind: u8, channel: u8| kind | (channel & 0x0F);
+        match self {
+            MidiMessage::NoteOn(note, velocity, channel) => vec![status(0x90, *channel), note & 0x7F, velocity & 0x7F],
+            MidiMessage::NoteOff(note, velocity, channel) => vec![status(0x80, *channel), note & 0x7F, velocity & 0x7F],
+            MidiMessage::PolyPressure(note, pressure, channel) => vec![status(0xA0, *channel), note & 0x7F, pressure & 0x7F],
+            MidiMessage::ControlChange(control, value, channel) => vec![status(0xB0, *channel), control & 0x7F, value & 0x7F],
+            MidiMessage::ProgramChange(program, channel) => vec![status(0xC0, *channel), program & 0x7F],
+            MidiMessage::ChannelPressure(pressure, channel) => vec![status(0xD0, *channel), pressure & 0x7F],
+            MidiMessage::PitchBend(value, channel) => {
+                let value = (*value).min(0x3FFF);
+                vec![status(0xE0, *channel), lsb(value), msb(value)]
+            },
+            MidiMessage::Sysex(payload) => {
+                let mut bytes = Vec::with_capacity(payload.len() + 2);
+                bytes.push(0xF0);
+                bytes.extend(payload.iter().map(|byte| byte & 0x7F));
+                bytes.push(0xF7);
+                bytes
+            },
+            MidiMessage::MtcQuarterFrame(piece, value) => vec![0xF1, ((piece & 0x07) << 4) | (value & 0x0F)],
+            MidiMessage::SongPosition(position) => {
+                let position = (*position).min(0x3FFF);
+                vec![0xF2, lsb(position), msb(position)]
+            },
+            MidiMessage::SongSelect(song) => vec![0xF3, song & 0x7F],
+            MidiMessage::TuneRequest => vec![0xF6],
+            MidiMessage::MidiClock => vec![0xF8],
+            MidiMessage::MidiStart => vec![0xFA],
+            MidiMessage::MidiContinue => vec![0xFB],
+            MidiMessage::MidiStop => vec![0xFC],
+            MidiMessage::ActiveSensing => vec![0xFE],
+            MidiMessage::Reset => vec![0xFF],
+        }
+    }
+
+    /// Parse a single complete message. Running status is not supported.
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (&status, data) = bytes.split_first().ok_or(DecodeError::Empty)?;
+        if status < 0x80 {
+            return Err(DecodeError::InvalidStatus(status));
+        }
+        if status == 0xF0 {
+            return match data.split_last() {
+                Some((0xF7, payload)) => MidiMessage::sysex(payload.to_vec()),
+                _ => Err(DecodeError::UnterminatedSysex),
+            };
+        }
+        let length = match status {
+            0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 2,
+            0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+            0xF6 | 0xF8 | 0xFA..=0xFC | 0xFE | 0xFF => 0,
+            _ => return Err(DecodeError::InvalidStatus(status)),
+        };
+        if data.len() < length {
+            return Err(DecodeError::Truncated(status));
+        }
+        let data = &data[..length];
+        if let Some(byte) = data.iter().find(|byte| **byte > 0x7F) {
+            return Err(DecodeError::InvalidData(*byte));
+        }
+        let word = || data[0] as u16 | (data[1] as u16) << 7;
+        let channel = status & 0x0F;
+        Ok(match status & 0xF0 {
+            // A NoteOn with a null velocity is a NoteOff, but is kept as sent.
+            0x90 => MidiMessage::NoteOn(data[0], data[1], channel),
+            0x80 => MidiMessage::NoteOff(data[0], data[1], channel),
+            0xA0 => MidiMessage::PolyPressure(data[0], data[1], channel),
+            0xB0 => MidiMessage::ControlChange(data[0], data[1], channel),
+            0xC0 => MidiMessage::ProgramChange(data[0], channel),
+            0xD0 => MidiMessage::ChannelPressure(data[0], channel),
+            0xE0 => MidiMessage::PitchBend(word(), channel),
+            _ => match status {
+                0xF1 => MidiMessage::MtcQuarterFrame(data[0] >> 4, data[0] & 0x0F),
+                0xF2 => MidiMessage::SongPosition(word()),
+                0xF3 => MidiMessage::SongSelect(data[0]),
+                0xF6 => MidiMessage::TuneRequest,
+                0xF8 => MidiMessage::MidiClock,
+                0xFA => MidiMessage::MidiStart,
+                0xFB => MidiMessage::MidiContinue,
+                0xFC => MidiMessage::MidiStop,
+                0xFE => MidiMessage::ActiveSensing,
+                _ => MidiMessage::Reset,
+            },
+        })
+    }
+
+    /// A system exclusive message, its payload given without the framing
+    /// bytes: those and any other byte with its high bit set are refused.
+    pub fn sysex(payload: Vec<u8>) -> Result<Self, DecodeError> {
+        match payload.iter().find(|byte| **byte > 0x7F) {
+            Some(byte) => Err(DecodeError::InvalidData(*byte)),
+            None => Ok(MidiMessage::Sysex(payload)),
+        }
+    }
+
+    /// Whether the message is a system realtime message, which may be sent
+    /// in between the bytes of any other message.
+    pub fn is_realtime(&self) -> bool {
+        matches!(self,
+            MidiMessage::MidiClock | MidiMessage::MidiStart | MidiMessage::MidiContinue
+            | MidiMessage::MidiStop | MidiMessage::ActiveSensing | MidiMessage::Reset
+        )
+    }
+}
+
+/// Set a 14-bit parameter through the given parameter number controllers,
+/// followed by the null parameter so that later data entries go nowhere.
+fn parameter(select: (u8, u8), parameter: u16, value: u16, channel: u8) -> Vec<MidiMessage> {
+    let (parameter, value) = (parameter.min(0x3FFF), value.min(0x3FFF));
+    vec![
+        MidiMessage::ControlChange(select.0, msb(parameter), channel),
+        MidiMessage::ControlChange(select.1, lsb(parameter), channel),
+        MidiMessage::ControlChange(6, msb(value), channel),
+        MidiMessage::ControlChange(38, lsb(value), channel),
+        MidiMessage::ControlChange(select.0, 127, channel),
+        MidiMessage::ControlChange(select.1, 127, channel),
+    ]
+}
+
+/// The control changes setting a non-registered parameter.
+pub fn nrpn(parameter_number: u16, value: u16, channel: u8) -> Vec<MidiMessage> {
+    parameter((99, 98), parameter_number, value, channel)
+}
+
+/// The control changes setting a registered parameter (0 is the pitch bend
+/// range, 1 and 2 the fine and coarse tuning).
+pub fn rpn(parameter_number: u16, value: u16, channel: u8) -> Vec<MidiMessage> {
+    parameter((101, 100), parameter_number, value, channel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: MidiMessage, bytes: &[u8]) {
+        assert_eq!(message.encode(), bytes);
+        assert_eq!(MidiMessage::decode(bytes), Ok(message));
+    }
+
+    #[test]
+    fn channel_voice() {
+        round_trip(MidiMessage::NoteOn(60, 100, 0), &[0x90, 60, 100]);
+        round_trip(MidiMessage::NoteOff(60, 64, 15), &[0x8F, 60, 64]);
+        round_trip(MidiMessage::PolyPressure(61, 20, 2), &[0xA2, 61, 20]);
+        round_trip(MidiMessage::ControlChange(7, 127, 3), &[0xB3, 7, 127]);
+        round_trip(MidiMessage::ProgramChange(12, 4), &[0xC4, 12]);
+        round_trip(MidiMessage::ChannelPressure(90, 5), &[0xD5, 90]);
+    }
+
+    #[test]
+    fn pitch_bend_is_14_bits() {
+        round_trip(MidiMessage::PitchBend(PITCH_BEND_CENTER, 0), &[0xE0, 0x00, 0x40]);
+        round_trip(MidiMessage::PitchBend(0, 1), &[0xE1, 0x00, 0x00]);
+        round_trip(MidiMessage::PitchBend(0x3FFF, 9), &[0xE9, 0x7F, 0x7F]);
+        round_trip(MidiMessage::PitchBend(1000, 0), &[0xE0, 0x68, 0x07]);
+        // Out of range values are clamped.
+        assert_eq!(MidiMessage::PitchBend(u16::MAX, 0).encode(), vec![0xE0, 0x7F, 0x7F]);
+    }
+
+    #[test]
+    fn out_of_range_values_are_masked() {
+        assert_eq!(MidiMessage::NoteOn(200, 128, 17).encode(), vec![0x91, 72, 0]);
+    }
+
+    #[test]
+    fn system_common() {
+        round_trip(MidiMessage::Sysex(vec![0x7E, 0x7F, 0x09, 0x01]), &[0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7]);
+        round_trip(MidiMessage::MtcQuarterFrame(7, 0x3), &[0xF1, 0x73]);
+        round_trip(MidiMessage::SongPosition(0x1234), &[0xF2, 0x34, 0x24]);
+        round_trip(MidiMessage::SongSelect(3), &[0xF3, 3]);
+        round_trip(MidiMessage::TuneRequest, &[0xF6]);
+    }
+
+    #[test]
+    fn system_realtime() {
+        for (message, byte) in [
+            (MidiMessage::MidiClock, 0xF8),
+            (MidiMessage::MidiStart, 0xFA),
+            (MidiMessage::MidiContinue, 0xFB),
+            (MidiMessage::MidiStop, 0xFC),
+            (MidiMessage::ActiveSensing, 0xFE),
+            (MidiMessage::Reset, 0xFF),
+        ] {
+            assert!(message.is_realtime());
+            round_trip(message, &[byte]);
+        }
+        assert!(!MidiMessage::TuneRequest.is_realtime());
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(MidiMessage::decode(&[]), Err(DecodeError::Empty));
+        assert_eq!(MidiMessage::decode(&[60, 100]), Err(DecodeError::InvalidStatus(60)));
+        assert_eq!(MidiMessage::decode(&[0xF4]), Err(DecodeError::InvalidStatus(0xF4)));
+        assert_eq!(MidiMessage::decode(&[0x90, 60]), Err(DecodeError::Truncated(0x90)));
+        assert_eq!(MidiMessage::decode(&[0x90, 60, 0x80]), Err(DecodeError::InvalidData(0x80)));
+        assert_eq!(MidiMessage::decode(&[0xF0, 0x01]), Err(DecodeError::UnterminatedSysex));
+        assert_eq!(MidiMessage::decode(&[0xF0, 0x81, 0xF7]), Err(DecodeError::InvalidData(0x81)));
+        assert_eq!(MidiMessage::sysex(vec![0xF0, 0x7E, 0xF7]), Err(DecodeError::InvalidData(0xF0)));
+    }
+
+    #[test]
+    fn parameter_numbers() {
+        assert_eq!(nrpn(0x0102, 0x0203, 1), vec![
+            MidiMessage::ControlChange(99, 0x02, 1),
+            MidiMessage::ControlChange(98, 0x02, 1),
+            MidiMessage::ControlChange(6, 0x04, 1),
+            MidiMessage::ControlChange(38, 0x03, 1),
+            MidiMessage::ControlChange(99, 127, 1),
+            MidiMessage::ControlChange(98, 127, 1),
+        ]);
+        // Pitch bend range of 12 semitones.
+        assert_eq!(rpn(0, 12 << 7, 0)[..4], [
+            MidiMessage::ControlChange(101, 0, 0),
+            MidiMessage::ControlChange(100, 0, 0),
+            MidiMessage::ControlChange(6, 12, 0),
+            MidiMessage::ControlChange(38, 0, 0),
+        ]);
+    }
+}
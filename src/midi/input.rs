@@ -0,0 +1,104 @@
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use mlua::prelude::*;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use crate::clock::ClockCommand;
+use crate::interpreter::EvalQueue;
+use crate::midi::MidiMessage;
+
+/// Names of the Lua callbacks in the registry.
+pub const ON_NOTE: &str = "eremit.on_note";
+pub const ON_CC: &str = "eremit.on_cc";
+
+/// Last value received for every `(channel, controller)` pair.
+pub type ControlValues = Arc<Mutex<HashMap<(u8, u8), u8>>>;
+
+/// Open MIDI input ports. Notes and control changes are handed to the Lua
+/// callbacks, clock messages to the clock thread, which decides whether to
+/// follow them.
+pub struct MidiInputs {
+    connections: Vec<MidiInputConnection<()>>,
+    controls: ControlValues,
+}
+
+impl MidiInputs {
+    /// Open every port of `ports` that can be found, reporting the others.
+    pub fn open(
+        ports: &[String],
+        queue: EvalQueue,
+        clock: Sender<ClockCommand>
+    ) -> Self {
+        let controls = ControlValues::default();
+        let mut connections = Vec::new();
+        for port in ports {
+            match connect(port, queue.clone(), clock.clone(), controls.clone()) {
+                Ok(connection) => {
+                    println!("Listening to MIDI input: {}", port);
+                    connections.push(connection);
+                },
+                Err(err) => println!("MIDI input error ({}): {}", port, err),
+            }
+        }
+        Self { connections, controls }
+    }
+
+    pub fn controls(&self) -> ControlValues {
+        self.controls.clone()
+    }
+}
+
+/// The names of the MIDI input ports available.
+pub fn input_ports() -> Result<Vec<String>, Box<dyn Error>> {
+    let midi_in = MidiInput::new("Eremit input")?;
+    Ok(midi_in.ports().iter().filter_map(|p| midi_in.port_name(p).ok()).collect())
+}
+
+fn connect(
+    port: &str,
+    queue: EvalQueue,
+    clock: Sender<ClockCommand>,
+    controls: ControlValues
+) -> Result<MidiInputConnection<()>, Box<dyn Error>> {
+    let mut midi_in = MidiInput::new("Eremit input")?;
+    midi_in.ignore(Ignore::ActiveSense);
+    let ports = midi_in.ports();
+    let found = ports.iter()
+        .find(|p| midi_in.port_name(p).is_ok_and(|name| name == port))
+        .ok_or("port not found")?;
+    let connection = midi_in.connect(found, "eremit-in", move |stamp, bytes, _| {
+        let Ok(message) = MidiMessage::decode(bytes) else {
+            return;
+        };
+        match message {
+            MidiMessage::MidiClock | MidiMessage::MidiStart | MidiMessage::MidiContinue
+            | MidiMessage::MidiStop | MidiMessage::SongPosition(_) => {
+                let _ = clock.send(ClockCommand::ClockIn(message, stamp));
+            },
+            MidiMessage::NoteOn(note, velocity, channel) => {
+                call(&queue, ON_NOTE, (note, velocity, channel));
+            },
+            MidiMessage::NoteOff(note, _, channel) => {
+                call(&queue, ON_NOTE, (note, 0, channel));
+            },
+            MidiMessage::ControlChange(controller, value, channel) => {
+                controls.lock().unwrap().insert((channel, controller), value);
+                call(&queue, ON_CC, (controller, value, channel));
+            },
+            _ => {}
+        }
+    }, ())?;
+    Ok(connection)
+}
+
+/// Call the Lua callback registered under `name`, if any, on the Lua thread.
+fn call(queue: &EvalQueue, name: &'static str, args: (u8, u8, u8)) {
+    let _ = queue.call(Box::new(move |lua: &Lua| {
+        match lua.named_registry_value::<Option<LuaFunction>>(name)? {
+            Some(callback) => callback.call::<_, ()>(args),
+            None => Ok(()),
+        }
+    }));
+}
@@ -0,0 +1,397 @@
+use num::One;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use crate::streams::{Hap, Pattern, Span, Time};
+
+/// The most times `!n` replicates a step.
+const MAX_REPLICATION: i128 = 1024;
+
+/// Mini-notation for rhythmic patterns, in the spirit of Tidal:
+///
+/// - `bd sn hh`: a sequence, the steps share the cycle evenly
+/// - `~`: a rest
+/// - `[sn sn]`: a subdivision, the group fits in a single step
+/// - `[bd, hh hh]`: layers of a group played at the same time
+/// - `<hh oh>`: alternation, one step per cycle
+/// - `{bd sn, hh hh hh}%4`: polymeter, every layer plays 4 steps per cycle
+/// - `hh*2`, `hh/2`: repeat a step faster or slower
+/// - `bd@3`, `bd _ _`: elongate a step by a relative weight
+/// - `bd!3`, `bd ! !`: replicate a step
+///
+/// A cycle is a bar of the clock.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Word(String),
+    Rest,
+    /// Steps with their relative weight, played one after the other in a cycle.
+    Sequence(Vec<(Node, Time)>),
+    /// Layers played at the same time.
+    Stack(Vec<Node>),
+    /// One item per cycle, in turn.
+    Alternation(Vec<Node>),
+    /// The node played `factor` times per cycle.
+    Fast(Box<Node>, Time),
+}
+
+impl Node {
+    /// The pattern described by the node.
+    pub fn to_pattern(&self) -> Pattern<String> {
+        match self {
+            Node::Word(word) => Pattern::pure(word.clone()),
+            Node::Rest => Pattern::silence(),
+            Node::Sequence(steps) => Pattern::timecat(
+                steps.iter().map(|(node, weight)| (*weight, node.to_pattern())).collect()
+            ),
+            Node::Stack(layers) => Pattern::stack(layers.iter().map(Node::to_pattern).collect()),
+            Node::Alternation(items) => Pattern::slowcat(items.iter().map(Node::to_pattern).collect()),
+            Node::Fast(node, factor) => node.to_pattern().fast(*factor),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mini-notation error at {}: {}", self.position, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+/// A parsed mini-notation string.
+#[derive(Debug, Clone)]
+pub struct Notation {
+    source: String,
+    root: Node,
+    pattern: Pattern<String>,
+}
+
+impl Notation {
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            position: 0,
+        };
+        let layers = parser.layers()?;
+        parser.skip_whitespace();
+        if let Some(c) = parser.peek() {
+            return Err(parser.error(format!("unexpected '{}'", c)));
+        }
+        let root = stack(layers.into_iter().map(sequence).collect());
+        Ok(Self {
+            source: source.to_string(),
+            pattern: root.to_pattern(),
+            root,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+
+    pub fn pattern(&self) -> &Pattern<String> {
+        &self.pattern
+    }
+
+    /// Every hap overlapping the span, in cycles.
+    pub fn query(&self, span: Span) -> Vec<Hap<String>> {
+        self.pattern.query(span)
+    }
+}
+
+type Steps = Vec<(Node, Time)>;
+
+fn sequence(steps: Steps) -> Node {
+    if steps.len() == 1 && steps[0].1.is_one() {
+        return steps.into_iter().next().unwrap().0;
+    }
+    Node::Sequence(steps)
+}
+
+fn stack(mut layers: Vec<Node>) -> Node {
+    if layers.len() == 1 {
+        return layers.pop().unwrap();
+    }
+    Node::Stack(layers)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn error(&self, message: String) -> ParseError {
+        ParseError {
+            position: self.position,
+            message,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.position += 1;
+                Ok(())
+            },
+            Some(c) => Err(self.error(format!("expected '{}', found '{}'", expected, c))),
+            None => Err(self.error(format!("expected '{}'", expected))),
+        }
+    }
+
+    /// Sequences separated by commas, up to a closing bracket or the end.
+    fn layers(&mut self) -> Result<Vec<Steps>, ParseError> {
+        let mut layers = vec![self.steps()?];
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(',') {
+                return Ok(layers);
+            }
+            self.position += 1;
+            layers.push(self.steps()?);
+        }
+    }
+
+    fn steps(&mut self) -> Result<Steps, ParseError> {
+        let mut steps: Steps = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None | Some(']') | Some('>') | Some('}') | Some(',') => break,
+                Some('_') => {
+                    self.position += 1;
+                    match steps.last_mut() {
+                        Some(step) => step.1 += 1,
+                        None => return Err(self.error("nothing to elongate".to_string())),
+                    }
+                },
+                Some('!') => {
+                    self.position += 1;
+                    match steps.last().cloned() {
+                        Some(step) => steps.push(step),
+                        None => return Err(self.error("nothing to replicate".to_string())),
+                    }
+                },
+                Some(_) => {
+                    let (node, weight, count) = self.step()?;
+                    for _ in 0..count {
+                        steps.push((node.clone(), weight));
+                    }
+                },
+            }
+        }
+        if steps.is_empty() {
+            return Err(self.error("empty sequence".to_string()));
+        }
+        Ok(steps)
+    }
+
+    /// A term followed by its modifiers, with its weight and replication count.
+    fn step(&mut self) -> Result<(Node, Time, usize), ParseError> {
+        let mut node = self.term()?;
+        let mut weight = Time::one();
+        let mut count = 1;
+        loop {
+            match self.peek() {
+                Some('*') => {
+                    self.position += 1;
+                    node = Node::Fast(Box::new(node), self.number()?);
+                },
+                Some('/') => {
+                    self.position += 1;
+                    let factor = self.number()?;
+                    node = Node::Fast(Box::new(node), factor.recip());
+                },
+                Some('@') => {
+                    self.position += 1;
+                    weight = self.number()?;
+                },
+                Some('!') if self.chars.get(self.position + 1).is_some_and(char::is_ascii_digit) => {
+                    self.position += 1;
+                    let start = self.position;
+                    let number = self.number()?;
+                    if !number.is_integer() || number > Time::from_integer(MAX_REPLICATION) {
+                        let text: String = self.chars[start..self.position].iter().collect();
+                        return Err(ParseError {
+                            position: start,
+                            message: format!("expected a whole number up to {}, found '{}'", MAX_REPLICATION, text),
+                        });
+                    }
+                    count = number.to_integer() as usize;
+                },
+                _ => return Ok((node, weight, count)),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Node, ParseError> {
+        match self.peek() {
+            Some('~') => {
+                self.position += 1;
+                Ok(Node::Rest)
+            },
+            Some('[') => {
+                self.position += 1;
+                let layers = self.layers()?;
+                self.expect(']')?;
+                Ok(stack(layers.into_iter().map(sequence).collect()))
+            },
+            Some('<') => {
+                self.position += 1;
+                let layers = self.layers()?;
+                self.expect('>')?;
+                Ok(stack(layers.into_iter()
+                    .map(|steps| Node::Alternation(steps.into_iter().map(|(node, _)| node).collect()))
+                    .collect()))
+            },
+            Some('{') => {
+                self.position += 1;
+                let layers = self.layers()?;
+                self.expect('}')?;
+                let steps_per_cycle = if self.peek() == Some('%') {
+                    self.position += 1;
+                    self.number()?
+                } else {
+                    Time::from_integer(layers[0].len() as i128)
+                };
+                Ok(stack(layers.into_iter()
+                    .map(|steps| {
+                        let factor = steps_per_cycle / steps.len() as i128;
+                        Node::Fast(Box::new(Node::Sequence(steps)), factor)
+                    })
+                    .collect()))
+            },
+            Some(c) if is_word_char(c) => {
+                let start = self.position;
+                while self.peek().is_some_and(is_word_char) {
+                    self.position += 1;
+                }
+                Ok(Node::Word(self.chars[start..self.position].iter().collect()))
+            },
+            Some(c) => Err(self.error(format!("unexpected '{}'", c))),
+            None => Err(self.error("unexpected end of pattern".to_string())),
+        }
+    }
+
+    /// A positive decimal number, as an exact fraction.
+    fn number(&mut self) -> Result<Time, ParseError> {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+            self.position += 1;
+        }
+        let text: String = self.chars[start..self.position].iter().collect();
+        let (integer, fraction) = text.split_once('.').unwrap_or((&text, ""));
+        let number = format!("{}{}", integer, fraction).parse::<i128>().ok()
+            .filter(|_| fraction.len() < 10)
+            .map(|numer| Time::new(numer, 10_i128.pow(fraction.len() as u32)));
+        match number {
+            Some(number) if number > Time::from_integer(0) => Ok(number),
+            _ => Err(ParseError {
+                position: start,
+                message: format!("expected a positive number, found '{}'", text),
+            }),
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '#' | '.' | ':' | '-')
+}
+
+/// The MIDI note of a word: a number (`60`) or a note name (`c`, `eb3`, `f#5`).
+/// Octaves follow the `c4 = 60` convention, the 4th octave being the default.
+pub fn note_number(word: &str) -> Option<u8> {
+    if let Ok(number) = word.parse::<u8>() {
+        return (number < 128).then_some(number);
+    }
+    let mut chars = word.chars().peekable();
+    let mut note: i32 = match chars.next()?.to_ascii_lowercase() {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    while let Some(&c) = chars.peek() {
+        match c {
+            '#' | 's' => note += 1,
+            'b' | 'f' => note -= 1,
+            _ => break,
+        }
+        chars.next();
+    }
+    let rest: String = chars.collect();
+    let octave: i32 = if rest.is_empty() { 4 } else { rest.parse().ok()? };
+    let number = (octave + 1) * 12 + note;
+    (0..128).contains(&number).then_some(number as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(source: &str) -> Vec<(Time, String)> {
+        let notation = Notation::parse(source).unwrap();
+        let mut words: Vec<_> = notation.query(Span::new(Time::from_integer(0), Time::from_integer(1)))
+            .into_iter()
+            .filter(Hap::has_onset)
+            .map(|hap| (hap.whole.unwrap().begin, hap.value))
+            .collect();
+        words.sort();
+        words
+    }
+
+    fn error(source: &str) -> (usize, String) {
+        let error = Notation::parse(source).unwrap_err();
+        (error.position, error.message)
+    }
+
+    #[test]
+    fn sequences_and_groups() {
+        let at = |numer, denom, word: &str| (Time::new(numer, denom), word.to_string());
+        assert_eq!(words("bd ~ [sn sn]"), vec![at(0, 1, "bd"), at(2, 3, "sn"), at(5, 6, "sn")]);
+        assert_eq!(words("bd@3 sn"), vec![at(0, 1, "bd"), at(3, 4, "sn")]);
+        assert_eq!(words("hh*2 bd!2"), vec![at(0, 1, "hh"), at(1, 6, "hh"), at(1, 3, "bd"), at(2, 3, "bd")]);
+        assert_eq!(words("<a b>"), vec![at(0, 1, "a")]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(error(""), (0, "empty sequence".to_string()));
+        assert_eq!(error("[bd sn"), (6, "expected ']'".to_string()));
+        assert_eq!(error("<a b]"), (4, "expected '>', found ']'".to_string()));
+        assert_eq!(error("bd ]"), (3, "unexpected ']'".to_string()));
+        assert_eq!(error("_ bd"), (1, "nothing to elongate".to_string()));
+        assert_eq!(error("! bd"), (1, "nothing to replicate".to_string()));
+        assert_eq!(error("bd*0"), (3, "expected a positive number, found '0'".to_string()));
+        assert_eq!(error("bd/x"), (3, "expected a positive number, found ''".to_string()));
+        assert_eq!(error("bd!0"), (3, "expected a positive number, found '0'".to_string()));
+        assert_eq!(error("bd!0.5"), (3, "expected a whole number up to 1024, found '0.5'".to_string()));
+        assert_eq!(error("hh bd!1000000000"), (6, "expected a whole number up to 1024, found '1000000000'".to_string()));
+        assert_eq!(error("bd $"), (3, "unexpected '$'".to_string()));
+    }
+}
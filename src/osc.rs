@@ -1,23 +1,369 @@
+use mlua::prelude::*;
 use rosc::encoder;
-use rosc::{OscMessage, OscPacket, OscType};
-use std::net::{SocketAddrV4, UdpSocket};
-use std::str::FromStr;
-use std::time::Duration;
-use std::{env, f32, thread};
+use rosc::{OscArray, OscBundle, OscMessage, OscPacket, OscTime, OscType};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-pub fn addr_from_string(addr: &str) -> SocketAddrV4 {
-    SocketAddrV4::from_str(addr).unwrap()
+use crate::streams::{BaseEventType, Event, Param, to_f64};
+
+pub mod server;
+
+/// Errors of the OSC transport.
+#[derive(Debug)]
+pub enum OscError {
+    /// An address that cannot be parsed or resolved.
+    Address(String),
+    UnknownTarget(String),
+    Encode(rosc::OscError),
+    Io(io::Error),
+}
+
+impl fmt::Display for OscError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OscError::Address(address) => write!(f, "invalid OSC address {}", address),
+            OscError::UnknownTarget(alias) => write!(f, "no OSC target called {}", alias),
+            OscError::Encode(err) => write!(f, "cannot encode OSC packet: {}", err),
+            OscError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for OscError {}
+
+impl From<io::Error> for OscError {
+    fn from(err: io::Error) -> Self {
+        OscError::Io(err)
+    }
+}
+
+impl From<rosc::OscError> for OscError {
+    fn from(err: rosc::OscError) -> Self {
+        OscError::Encode(err)
+    }
+}
+
+/// Resolve `host:port`, the host being an IPv4 address, a bracketed IPv6
+/// address (`[::1]:57120`) or a host name.
+pub fn resolve(address: &str) -> Result<SocketAddr, OscError> {
+    address.to_socket_addrs()
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .ok_or_else(|| OscError::Address(address.to_string()))
+}
+
+pub fn create_socket(address: &str) -> Result<UdpSocket, OscError> {
+    Ok(UdpSocket::bind(resolve(address)?)?)
+}
+
+pub fn send_message(socket: &UdpSocket, message: OscMessage, to_address: &str) -> Result<(), OscError> {
+    let encoded = encoder::encode(&OscPacket::Message(message))?;
+    socket.send_to(&encoded, resolve(to_address)?)?;
+    Ok(())
+}
+
+/// Alias of the target used by events that do not name one.
+pub const DEFAULT_TARGET: &str = "default";
+
+/// Where SuperDirt listens by default.
+pub const SUPERDIRT: &str = "127.0.0.1:57120";
+
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+/// Frame a packet for a stream transport, as in OSC 1.1: SLIP with an END
+/// byte on both sides.
+fn slip_encode(packet: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(packet.len() + 2);
+    framed.push(SLIP_END);
+    for &byte in packet {
+        match byte {
+            SLIP_END => framed.extend([SLIP_ESC, SLIP_ESC_END]),
+            SLIP_ESC => framed.extend([SLIP_ESC, SLIP_ESC_ESC]),
+            byte => framed.push(byte),
+        }
+    }
+    framed.push(SLIP_END);
+    framed
+}
+
+/// Packets waiting for a TCP destination before new ones are dropped.
+const TCP_QUEUE: usize = 256;
+
+/// Time to wait before connecting again after a failure, doubled on every
+/// failure up to the maximum.
+const TCP_RETRY: (Duration, Duration) = (Duration::from_millis(100), Duration::from_secs(5));
+
+/// How long connecting or writing may take before the connection is
+/// considered dead.
+const TCP_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn connect_tcp(address: &SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(address, TCP_TIMEOUT)?;
+    stream.set_nodelay(true)?;
+    stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+    Ok(stream)
 }
 
-pub fn create_socket(addr: &str) -> UdpSocket {
-    let addr = addr_from_string(addr);
-    let socket = UdpSocket::bind(addr).unwrap();
-    socket.set_nonblocking(true).unwrap();
-    socket
+/// Write the framed packets sent to `spec` from a thread of their own, so
+/// that a slow or dead receiver never holds up the other outputs. The
+/// connection is opened on first use and reopened after an error, backing
+/// off meanwhile: packets arriving in between are dropped, being late
+/// anyway. The thread ends with the destination.
+fn spawn_tcp_writer(spec: String, address: SocketAddr) -> SyncSender<Vec<u8>> {
+    let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(TCP_QUEUE);
+    thread::spawn(move || {
+        let mut stream: Option<TcpStream> = None;
+        let (mut retry, mut backoff) = (Instant::now(), TCP_RETRY.0);
+        for packet in receiver {
+            if stream.is_none() {
+                if Instant::now() < retry {
+                    continue;
+                }
+                match connect_tcp(&address) {
+                    Ok(connected) => stream = Some(connected),
+                    Err(err) => {
+                        println!("OSC error ({}): {}", spec, err);
+                        retry = Instant::now() + backoff;
+                        backoff = (backoff * 2).min(TCP_RETRY.1);
+                        continue;
+                    },
+                }
+            }
+            let Some(connected) = stream.as_mut() else {
+                continue;
+            };
+            match connected.write_all(&packet) {
+                Ok(()) => backoff = TCP_RETRY.0,
+                Err(err) => {
+                    println!("OSC error ({}): {}", spec, err);
+                    stream = None;
+                    retry = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(TCP_RETRY.1);
+                },
+            }
+        }
+    });
+    sender
 }
 
-pub fn send_message(socket: &UdpSocket, message: OscMessage, to_addr: &str) {
-    let packet = OscPacket::Message(message);
-    let encoded = encoder::encode(&packet).unwrap();
-    socket.send_to(&encoded, addr_from_string(to_addr)).unwrap()
-}
\ No newline at end of file
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// A named destination. TCP ones are written to by a thread of their own.
+struct Destination {
+    /// The address as it was given, `tcp://` prefix included.
+    spec: String,
+    transport: Transport,
+    address: SocketAddr,
+    tcp: Option<SyncSender<Vec<u8>>>,
+}
+
+impl Destination {
+    /// Parse `host:port`, `udp://host:port` or `tcp://host:port`.
+    fn parse(spec: &str) -> Result<Self, OscError> {
+        let (transport, address) = match spec.split_once("://") {
+            None => (Transport::Udp, spec),
+            Some(("udp", address)) => (Transport::Udp, address),
+            Some(("tcp", address)) => (Transport::Tcp, address),
+            Some(_) => return Err(OscError::Address(spec.to_string())),
+        };
+        let address = resolve(address)?;
+        Ok(Self {
+            spec: spec.to_string(),
+            transport,
+            address,
+            tcp: (transport == Transport::Tcp).then(|| spawn_tcp_writer(spec.to_string(), address)),
+        })
+    }
+
+    /// Hand a packet to the writer thread, never waiting for it.
+    fn send_tcp(&self, packet: &[u8]) -> Result<(), OscError> {
+        let Some(tcp) = &self.tcp else {
+            return Err(OscError::Address(self.spec.clone()));
+        };
+        tcp.try_send(slip_encode(packet)).map_err(|err| {
+            let kind = match err {
+                TrySendError::Full(_) => io::ErrorKind::WouldBlock,
+                TrySendError::Disconnected(_) => io::ErrorKind::BrokenPipe,
+            };
+            OscError::Io(io::Error::new(kind, format!("{} is not keeping up", self.spec)))
+        })
+    }
+}
+
+/// OSC destinations addressed by alias. UDP ones are all sent to from a
+/// single socket per address family.
+pub struct OscTargets {
+    socket: UdpSocket,
+    socket_v6: Option<UdpSocket>,
+    targets: HashMap<String, Destination>,
+    /// Send timetagged bundles ahead of time rather than bare messages on time.
+    bundles: bool,
+}
+
+impl OscTargets {
+    pub fn new() -> Result<Self, OscError> {
+        Ok(Self {
+            socket: UdpSocket::bind("0.0.0.0:0")?,
+            // Hosts without IPv6 simply cannot reach IPv6 targets.
+            socket_v6: UdpSocket::bind("[::]:0").ok(),
+            targets: HashMap::new(),
+            bundles: true,
+        })
+    }
+
+    pub fn bundles(&self) -> bool {
+        self.bundles
+    }
+
+    pub fn set_bundles(&mut self, enabled: bool) {
+        self.bundles = enabled;
+    }
+
+    /// Send the messages of `alias` to `address`: `127.0.0.1:57120`,
+    /// `[::1]:57120`, `localhost:57120` or `tcp://host:port`.
+    pub fn set_target(&mut self, alias: &str, address: &str) -> Result<(), OscError> {
+        self.targets.insert(alias.to_string(), Destination::parse(address)?);
+        Ok(())
+    }
+
+    /// `set_target`, for targets shared with the dispatcher. The address is
+    /// resolved before `targets`, locked for every message, is locked to
+    /// insert it.
+    pub fn set_target_shared(targets: &Mutex<Self>, alias: &str, address: &str) -> Result<(), OscError> {
+        let destination = Destination::parse(address)?;
+        targets.lock().unwrap().targets.insert(alias.to_string(), destination);
+        Ok(())
+    }
+
+    pub fn remove_target(&mut self, alias: &str) -> Result<(), OscError> {
+        self.targets.remove(alias)
+            .map(|_| ())
+            .ok_or_else(|| OscError::UnknownTarget(alias.to_string()))
+    }
+
+    pub fn targets(&self) -> Vec<(String, String)> {
+        let mut targets: Vec<(String, String)> = self.targets.iter()
+            .map(|(alias, destination)| (alias.clone(), destination.spec.clone()))
+            .collect();
+        targets.sort();
+        targets
+    }
+
+    /// Send `message` to a target, in a bundle when `timetag` is given.
+    pub fn send(&mut self,
+        target: Option<&str>,
+        message: OscMessage,
+        timetag: Option<OscTime>
+    ) -> Result<(), OscError> {
+        let alias = target.unwrap_or(DEFAULT_TARGET);
+        let destination = self.targets.get_mut(alias)
+            .ok_or_else(|| OscError::UnknownTarget(alias.to_string()))?;
+        let packet = match timetag {
+            Some(timetag) => OscPacket::Bundle(OscBundle {
+                timetag,
+                content: vec![OscPacket::Message(message)],
+            }),
+            None => OscPacket::Message(message),
+        };
+        let encoded = encoder::encode(&packet)?;
+        match destination.transport {
+            Transport::Tcp => destination.send_tcp(&encoded),
+            Transport::Udp => {
+                let socket = match destination.address {
+                    SocketAddr::V4(_) => &self.socket,
+                    SocketAddr::V6(_) => self.socket_v6.as_ref()
+                        .ok_or_else(|| OscError::Address(destination.spec.clone()))?,
+                };
+                socket.send_to(&encoded, destination.address)?;
+                Ok(())
+            },
+        }
+    }
+}
+
+/// The OSC argument of a Lua value. Integers that do not fit 32 bits are
+/// sent as 64 bits, tables as arrays.
+pub fn lua_argument(value: LuaValue) -> LuaResult<OscType> {
+    match value {
+        LuaValue::Nil => Ok(OscType::Nil),
+        LuaValue::Boolean(value) => Ok(OscType::Bool(value)),
+        LuaValue::Integer(value) => Ok(i32::try_from(value)
+            .map(OscType::Int)
+            .unwrap_or(OscType::Long(value))),
+        LuaValue::Number(value) => Ok(OscType::Float(value as f32)),
+        LuaValue::String(value) => Ok(OscType::String(value.to_str()?.to_string())),
+        LuaValue::Table(table) => {
+            let content = table.sequence_values::<LuaValue>()
+                .map(|value| lua_argument(value?))
+                .collect::<LuaResult<Vec<OscType>>>()?;
+            Ok(OscType::Array(OscArray { content }))
+        },
+        other => Err(LuaError::RuntimeError(
+            format!("cannot send a {} over OSC", other.type_name())
+        )),
+    }
+}
+
+/// The timetag of `time`, a Link time in microseconds, `now` being the
+/// current Link time.
+pub fn timetag(time: i64, now: i64) -> Option<OscTime> {
+    let offset = Duration::from_micros(time.abs_diff(now));
+    let time = match time >= now {
+        true => SystemTime::now().checked_add(offset)?,
+        false => SystemTime::now().checked_sub(offset)?,
+    };
+    OscTime::try_from(time).ok()
+}
+
+fn argument(param: &Param) -> OscType {
+    match param {
+        Param::Int(value) => OscType::Int(*value),
+        Param::Long(value) => OscType::Long(*value),
+        Param::Float(value) => OscType::Float(*value),
+        Param::Str(value) => OscType::String(value.clone()),
+    }
+}
+
+/// The message of an OSC event, its parameters being sent as key/value
+/// pairs. SuperDirt events also get the timing fields it expects: cycles
+/// per second, position in cycles and duration in seconds, from the `tempo`
+/// the event starts at and the `seconds` it lasts, ramps included.
+pub fn event_message(event: &Event, tempo: f64, seconds: f64, quantum: f64) -> Option<OscMessage> {
+    let mut args = Vec::new();
+    let addr = match event.event_type() {
+        BaseEventType::Osc(address) => address.clone(),
+        BaseEventType::Dirt => {
+            args.extend([
+                OscType::String("cps".to_string()),
+                OscType::Float((tempo / 60.0 / quantum) as f32),
+                OscType::String("cycle".to_string()),
+                OscType::Float((to_f64(event.begin()) / quantum) as f32),
+                OscType::String("delta".to_string()),
+                OscType::Float(seconds as f32),
+            ]);
+            if !event.params().iter().any(|(key, _)| key == "orbit") {
+                args.extend([OscType::String("orbit".to_string()), OscType::Int(0)]);
+            }
+            "/dirt/play".to_string()
+        },
+        _ => return None,
+    };
+    for (key, value) in event.params() {
+        args.push(OscType::String(key.clone()));
+        args.push(argument(value));
+    }
+    Some(OscMessage { addr, args })
+}
@@ -0,0 +1,208 @@
+use rosc::decoder;
+use rosc::{OscMessage, OscPacket, OscType};
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use crate::clock::{ClockCommand, ClockReply, Quantize, StreamSpec};
+use crate::interpreter::{EvalQueue, Evaluation};
+use crate::mininotation::Notation;
+use crate::osc;
+use crate::streams::{self, StreamContent};
+
+/// Remote control over OSC. Every message is answered on the address it
+/// came from, with `/eremit/result` or `/eremit/error`:
+///
+/// - `/eremit/eval <code>`: evaluate Lua code, what it prints being sent
+///   back first as `/eremit/output`
+/// - `/eremit/tempo [<bpm>]`: set the tempo, or ask for it
+/// - `/eremit/play`: start or stop the transport
+/// - `/eremit/stream/<name> <notation> [<quantize>]`: replace a stream
+/// - `/eremit/stream/<name>/add <notation> [<quantize>]`: layer on a stream
+/// - `/eremit/stream/<name>/remove [<quantize>]`: remove a stream
+pub struct OscServer {
+    socket: UdpSocket,
+    queue: EvalQueue,
+    clock: Sender<ClockCommand>,
+    /// Evaluations waiting for the Lua thread, with who to answer.
+    responder: Sender<(Receiver<Evaluation>, SocketAddr)>,
+}
+
+impl OscServer {
+    /// Listen on `address` (`127.0.0.1:7777`) on a thread of its own.
+    pub fn spawn(
+        address: &str,
+        queue: EvalQueue,
+        clock: Sender<ClockCommand>
+    ) -> Result<(), Box<dyn Error>> {
+        let socket = osc::create_socket(address)?;
+        let (responder, pending) = mpsc::channel();
+        let replies = socket.try_clone()?;
+        thread::spawn(move || respond(replies, pending));
+        let server = Self {
+            socket,
+            queue,
+            clock,
+            responder,
+        };
+        println!("Listening to OSC on {}", address);
+        thread::spawn(move || server.run());
+        Ok(())
+    }
+
+    fn run(&self) {
+        let mut buffer = [0u8; decoder::MTU];
+        loop {
+            let (size, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(err) => {
+                    println!("OSC server error: {}", err);
+                    continue;
+                }
+            };
+            match decoder::decode_udp(&buffer[..size]) {
+                Ok((_, packet)) => self.handle_packet(packet, from),
+                Err(err) => reply(&self.socket, from, "/eremit/error", format!("invalid OSC packet: {}", err)),
+            }
+        }
+    }
+
+    fn handle_packet(&self, packet: OscPacket, from: SocketAddr) {
+        match packet {
+            OscPacket::Message(message) => {
+                let address = message.addr.clone();
+                match self.handle_message(message, from) {
+                    Ok(Some(result)) => reply(&self.socket, from, "/eremit/result", result),
+                    Ok(None) => {},
+                    Err(err) => reply(&self.socket, from, "/eremit/error", format!("{}: {}", address, err)),
+                }
+            },
+            OscPacket::Bundle(bundle) => {
+                for packet in bundle.content {
+                    self.handle_packet(packet, from);
+                }
+            },
+        }
+    }
+
+    /// Handle a message, returning the result to send back if it is known
+    /// already. Evaluations reply on their own once the Lua thread is done.
+    fn handle_message(&self, message: OscMessage, from: SocketAddr) -> Result<Option<String>, Box<dyn Error>> {
+        let path: Vec<&str> = message.addr.trim_start_matches('/').split('/').collect();
+        let args = message.args;
+        match path.as_slice() {
+            ["eremit", "eval"] => {
+                let evaluation = self.queue.submit(string_arg(&args, 0)?, "osc");
+                self.responder.send((evaluation, from)).map_err(|_| "the OSC server is stopping")?;
+                Ok(None)
+            },
+            ["eremit", "tempo"] if args.is_empty() => {
+                let (reply_sender, reply_receiver) = mpsc::channel::<ClockReply>();
+                self.send_to_clock(ClockCommand::GetTempo(reply_sender))?;
+                match reply_receiver.recv()? {
+                    ClockReply::Tempo(tempo) => Ok(Some(tempo.to_string())),
+                    reply => Err(format!("unexpected reply from the clock: {:?}", reply).into()),
+                }
+            },
+            ["eremit", "tempo"] => {
+                let tempo = float_arg(&args, 0)?;
+                self.send_to_clock(ClockCommand::SetTempo(tempo))?;
+                Ok(Some(tempo.to_string()))
+            },
+            ["eremit", "play"] => {
+                self.send_to_clock(ClockCommand::Play)?;
+                Ok(Some("ok".to_string()))
+            },
+            ["eremit", "stream", name] => {
+                let spec = stream_spec(name, &args)?;
+                self.send_to_clock(ClockCommand::AddStream(spec))?;
+                Ok(Some("ok".to_string()))
+            },
+            ["eremit", "stream", name, "add"] => {
+                let spec = stream_spec(name, &args)?;
+                self.send_to_clock(ClockCommand::UpdateStream(spec))?;
+                Ok(Some("ok".to_string()))
+            },
+            ["eremit", "stream", name, "remove"] => {
+                let quantize = quantize_arg(&args, 0)?;
+                self.send_to_clock(ClockCommand::RemoveStream(name.to_string(), quantize))?;
+                Ok(Some("ok".to_string()))
+            },
+            _ => Err("unknown address".into()),
+        }
+    }
+
+    fn send_to_clock(&self, command: ClockCommand) -> Result<(), Box<dyn Error>> {
+        self.clock.send(command).map_err(|_| "the clock is not running".into())
+    }
+}
+
+/// Answer evaluations as they are done, which is in the order they were
+/// submitted, the Lua thread taking them one at a time.
+fn respond(socket: UdpSocket, pending: Receiver<(Receiver<Evaluation>, SocketAddr)>) {
+    for (evaluation, from) in pending {
+        let Ok(evaluation) = evaluation.recv() else {
+            reply(&socket, from, "/eremit/error", "the interpreter is not running".to_string());
+            continue;
+        };
+        if !evaluation.output.is_empty() {
+            reply(&socket, from, "/eremit/output", evaluation.output);
+        }
+        match evaluation.error {
+            Some(error) => reply(&socket, from, "/eremit/error", error),
+            None => reply(&socket, from, "/eremit/result", evaluation.values.join("\t")),
+        }
+    }
+}
+
+fn reply(socket: &UdpSocket, to: SocketAddr, address: &str, text: String) {
+    let packet = OscPacket::Message(OscMessage {
+        addr: address.to_string(),
+        args: vec![OscType::String(text)],
+    });
+    let sent = rosc::encoder::encode(&packet)
+        .map_err(|err| err.to_string())
+        .and_then(|bytes| socket.send_to(&bytes, to).map_err(|err| err.to_string()));
+    if let Err(err) = sent {
+        println!("OSC server error: {}", err);
+    }
+}
+
+fn string_arg(args: &[OscType], index: usize) -> Result<String, Box<dyn Error>> {
+    match args.get(index) {
+        Some(OscType::String(value)) => Ok(value.clone()),
+        Some(other) => Err(format!("argument {} should be a string, not {:?}", index, other).into()),
+        None => Err(format!("missing argument {}", index).into()),
+    }
+}
+
+fn float_arg(args: &[OscType], index: usize) -> Result<f64, Box<dyn Error>> {
+    match args.get(index) {
+        Some(OscType::Float(value)) => Ok(*value as f64),
+        Some(OscType::Double(value)) => Ok(*value),
+        Some(OscType::Int(value)) => Ok(*value as f64),
+        Some(other) => Err(format!("argument {} should be a number, not {:?}", index, other).into()),
+        None => Err(format!("missing argument {}", index).into()),
+    }
+}
+
+/// An optional quantize argument, as given in Lua: `now`, `beat`, `bar` or
+/// a number of bars.
+fn quantize_arg(args: &[OscType], index: usize) -> Result<Quantize, Box<dyn Error>> {
+    match args.get(index) {
+        None => Ok(Quantize::default()),
+        Some(OscType::String(name)) => Quantize::from_name(name)
+            .ok_or_else(|| format!("unknown quantize value: {}", name).into()),
+        Some(_) => Ok(Quantize::bars(float_arg(args, index)?)?),
+    }
+}
+
+fn stream_spec(name: &str, args: &[OscType]) -> Result<StreamSpec, Box<dyn Error>> {
+    let notation = Notation::parse(&string_arg(args, 0)?)?;
+    Ok(StreamSpec {
+        name: name.to_string(),
+        content: StreamContent::Pattern(streams::notation_events(&notation, 0, 100)),
+        quantize: quantize_arg(args, 1)?,
+    })
+}
@@ -0,0 +1,476 @@
+use std::fmt::{self, Display};
+use std::sync::mpsc::Receiver;
+
+use crate::clock::{Clock, TICK};
+use crate::dispatcher::{DispatcherMessage, OutputMessage};
+use crate::interpreter::EvalQueue;
+use crate::midi::MidiMessage;
+use crate::time_source::VirtualTime;
+
+/// A message the clock sent to the outputs, with the beat it falls on and
+/// its time from the start of the render, in microseconds.
+pub struct Rendered {
+    pub beat: f64,
+    pub micros: i64,
+    pub message: OutputMessage,
+}
+
+impl Display for Rendered {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>9.4} {:>11.3}ms {}", self.beat, self.micros as f64 / 1000.0, self.message)
+    }
+}
+
+/// Drives a clock on virtual time as fast as it goes, collecting what it
+/// sends to the dispatcher instead of playing it. The result only depends
+/// on what was played, never on how busy the machine was.
+pub struct Render {
+    clock: Clock,
+    time: VirtualTime,
+    output: Receiver<DispatcherMessage>,
+    /// The Lua thread, waited for after every step so that callbacks come
+    /// back to the clock in time however fast it goes.
+    queue: Option<EvalQueue>,
+    /// Virtual time between two steps, in microseconds.
+    step: i64,
+    /// Host time of the start of the render.
+    start: i64,
+    /// What was scheduled past the end of the last render.
+    pending: Vec<Rendered>,
+}
+
+impl Render {
+    /// `clock` must follow `time`, and send to the dispatcher `output`
+    /// listens to.
+    pub fn new(clock: Clock, time: VirtualTime, output: Receiver<DispatcherMessage>) -> Self {
+        let start = time.now();
+        Self {
+            clock,
+            time,
+            output,
+            queue: None,
+            step: TICK as i64 * 1000,
+            start,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn with_queue(mut self, queue: EvalQueue) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Step by `step` microseconds rather than a clock tick.
+    #[cfg(test)]
+    pub fn with_step(mut self, step: i64) -> Self {
+        self.step = step.max(1);
+        self
+    }
+
+    /// Play until the end of bar `bars`, starting the transport if a
+    /// command did not already, and return what was sent in order.
+    pub fn bars(&mut self, bars: u32) -> Vec<Rendered> {
+        if !self.clock.source().is_playing() {
+            self.clock.step();
+            self.clock.set_playing(true);
+        }
+        self.until(bars as f64 * self.clock.quantum)
+    }
+
+    /// Step until the clock reaches `beat`. Messages at that beat are kept
+    /// for the next render, except note offs ending what came before.
+    pub fn until(&mut self, beat: f64) -> Vec<Rendered> {
+        let quantum = self.clock.quantum;
+        loop {
+            self.clock.step();
+            if let Some(queue) = &self.queue {
+                let _ = queue.sync();
+            }
+            self.collect();
+            // The lookahead reaches past the end by now.
+            if self.clock.source().beat_at_time(self.time.now(), quantum) >= beat {
+                break;
+            }
+            self.time.advance(self.step);
+        }
+        self.pending.sort_by_key(|rendered| rendered.micros);
+        // Compared in microseconds, as scheduled: beats read back from the
+        // time may fall a hair short of where they belong.
+        let end = self.clock.tempo_map().time_at_beat(beat) - self.start;
+        let (done, pending) = self.pending.drain(..).partition(|rendered| {
+            rendered.micros < end || (rendered.micros == end && is_note_off(&rendered.message))
+        });
+        self.pending = pending;
+        done
+    }
+
+    /// Take what the clock sent, as the dispatcher would have. Beats are
+    /// those of the timeline at the time it was sent, ramps included.
+    fn collect(&mut self) {
+        let now = self.time.now();
+        while let Ok(message) = self.output.try_recv() {
+            let (time, message) = match message {
+                DispatcherMessage::Schedule(time, message) => (time, message),
+                DispatcherMessage::Now(message) => (now, message),
+                DispatcherMessage::Clear => {
+                    self.pending.retain(|rendered| rendered.micros <= now - self.start);
+                    continue;
+                },
+                DispatcherMessage::Ack(reply) => {
+                    let _ = reply.send(());
+                    continue;
+                },
+                DispatcherMessage::SetLatency(..) | DispatcherMessage::ReleaseNotes => continue,
+            };
+            self.pending.push(Rendered {
+                beat: self.clock.tempo_map().beat_at_time(time),
+                micros: time - self.start,
+                message,
+            });
+        }
+    }
+}
+
+/// `Eremit render <file.lua> [bars]`.
+pub struct RenderArgs {
+    pub path: String,
+    pub bars: u32,
+}
+
+impl RenderArgs {
+    /// The arguments following `render`. Four bars are rendered unless told
+    /// otherwise.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let usage = || "usage: Eremit render <file.lua> [bars]".to_string();
+        let path = args.first().ok_or_else(usage)?.clone();
+        let bars = match args.get(1) {
+            Some(bars) => bars.parse().map_err(|_| usage())?,
+            None => 4,
+        };
+        Ok(Self { path, bars })
+    }
+}
+
+/// Evaluate the file of `args`, print what it plays during the bars asked
+/// for, then stop the Lua thread. Anything the file prints goes to stderr.
+pub fn run(mut render: Render, args: RenderArgs, queue: EvalQueue) {
+    match std::fs::read_to_string(&args.path) {
+        Ok(code) => {
+            let evaluation = queue.evaluate(code, &args.path);
+            eprint!("{}", evaluation.output);
+            match evaluation.error {
+                Some(error) => eprintln!("error: {}", error),
+                None => {
+                    for rendered in render.bars(args.bars) {
+                        println!("{}", rendered);
+                    }
+                },
+            }
+        },
+        Err(err) => eprintln!("error: {}: {}", args.path, err),
+    }
+    queue.exit();
+}
+
+fn is_note_off(message: &OutputMessage) -> bool {
+    matches!(message, OutputMessage::Midi(_, MidiMessage::NoteOff(..)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusty_link::AblLink;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::mpsc::{self, Sender};
+    use std::thread;
+    use crate::clock::{ClockCommand, Quantize, StreamSpec};
+    use crate::interpreter::Interpreter;
+    use crate::mininotation::Notation;
+    use crate::routines;
+    use crate::streams::{self, BaseEventType, Event, Param, StreamContent, Time};
+    use crate::tempo::{Curve, TempoPoint};
+    use crate::time_source::InternalSource;
+    use crate::timers::{self, Period, Timers};
+
+    /// A Lua thread with the routine functions, as the render command has.
+    fn lua_thread() -> EvalQueue {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut interpreter = Interpreter::new();
+            routines::install(&interpreter.lua).unwrap();
+            sender.send(interpreter.queue()).unwrap();
+            let _ = interpreter.serve();
+        });
+        receiver.recv().unwrap()
+    }
+
+    fn render(tempo: f64) -> (Render, Sender<ClockCommand>) {
+        let time = VirtualTime::default();
+        let source = Box::new(InternalSource::on_virtual_time(time.clone(), tempo));
+        let (sender, receiver) = mpsc::channel();
+        let (dispatcher, output) = mpsc::channel();
+        let queue = lua_thread();
+        let timers = Timers::new(queue.clone(), sender.clone(), 250.0);
+        let link = Arc::new(AblLink::new(tempo));
+        let clock = Clock::new(link, source, receiver, dispatcher, timers, 100.0);
+        (Render::new(clock, time, output).with_queue(queue), sender)
+    }
+
+    /// Hand the function `code` evaluates to over to `register`, on the
+    /// Lua thread.
+    fn define<F>(render: &Render, code: &str, register: F)
+    where
+        F: for<'lua> FnOnce(&'lua mlua::Lua, mlua::Function<'lua>) -> mlua::Result<()> + Send + 'static,
+    {
+        let code = code.to_string();
+        render.queue.as_ref().unwrap().call(Box::new(move |lua: &mlua::Lua| {
+            register(lua, lua.load(code).eval()?)
+        })).unwrap();
+    }
+
+    /// Start the routine `code` evaluates to, as `spawn` does.
+    fn spawn(render: &Render, clock: &Sender<ClockCommand>, id: u64, code: &str) {
+        define(render, code, move |lua, routine| routines::spawn(lua, id, routine));
+        clock.send(ClockCommand::Spawn(id)).unwrap();
+    }
+
+    fn notes(name: &str, notation: &str) -> ClockCommand {
+        let notation = Notation::parse(notation).unwrap();
+        ClockCommand::AddStream(StreamSpec {
+            name: name.to_string(),
+            content: StreamContent::Pattern(streams::notation_events(&notation, 0, 100)),
+            quantize: Quantize::Bar,
+        })
+    }
+
+    fn text(rendered: &[Rendered]) -> String {
+        rendered.iter().map(|rendered| format!("{}\n", rendered)).collect()
+    }
+
+    /// Compare with `golden/<name>.txt`, rewritten instead when
+    /// `EREMIT_BLESS` is set.
+    fn golden(name: &str, rendered: &[Rendered]) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("src/render/golden")
+            .join(format!("{}.txt", name));
+        let actual = text(rendered);
+        if std::env::var_os("EREMIT_BLESS").is_some() {
+            std::fs::write(&path, actual).unwrap();
+            return;
+        }
+        let expected = std::fs::read_to_string(&path)
+            .unwrap_or_else(|_| panic!("missing {}, run with EREMIT_BLESS=1", path.display()));
+        assert_eq!(actual, expected, "{} changed, run with EREMIT_BLESS=1 if it should", name);
+    }
+
+    #[test]
+    fn notation() {
+        let (mut render, clock) = render(120.0);
+        clock.send(notes("melody", "c4 [e4 g4] ~ <c5 b4>")).unwrap();
+        golden("notation", &render.bars(2));
+    }
+
+    #[test]
+    fn events_and_osc() {
+        let (mut render, clock) = render(90.0);
+        let kick = Event::new(Time::from_integer(0), Time::new(1, 2), BaseEventType::NoteOn, vec![36, 127])
+            .with_channel(9);
+        let snare = Event::new(Time::new(3, 2), Time::from_integer(2), BaseEventType::NoteOn, vec![38, 90])
+            .with_channel(9)
+            .with_output(Some("drums".to_string()));
+        let hat = Event::new(Time::new(1, 2), Time::from_integer(1), BaseEventType::Dirt, Vec::new())
+            .with_param("s", Param::Str("hh".to_string()))
+            .with_param("gain", Param::Float(0.8));
+        clock.send(ClockCommand::AddStream(StreamSpec {
+            name: "drums".to_string(),
+            content: StreamContent::Events(vec![kick, snare, hat]),
+            quantize: Quantize::Now,
+        })).unwrap();
+        golden("events_and_osc", &render.bars(2));
+    }
+
+    #[test]
+    fn swap_on_the_next_bar() {
+        let (mut render, clock) = render(120.0);
+        clock.send(notes("bass", "c2*2")).unwrap();
+        let mut rendered = render.bars(1);
+        rendered.extend(render.until(6.0));
+        clock.send(notes("bass", "g1 ~ g1 a1")).unwrap();
+        rendered.extend(render.bars(3));
+        golden("swap_on_the_next_bar", &rendered);
+    }
+
+    #[test]
+    fn tempo_change() {
+        let (mut render, clock) = render(120.0);
+        clock.send(notes("pulse", "c4*4")).unwrap();
+        let mut rendered = render.bars(1);
+        clock.send(ClockCommand::SetTempo(60.0)).unwrap();
+        rendered.extend(render.bars(2));
+        golden("tempo_change", &rendered);
+    }
+
+    #[test]
+    fn tempo_ramp() {
+        let (mut render, clock) = render(120.0);
+        clock.send(notes("pulse", "c4*4")).unwrap();
+        let mut rendered = render.bars(1);
+        let ramp = TempoPoint { beat: Time::from_integer(4), tempo: 180.0, curve: Curve::Linear };
+        clock.send(ClockCommand::Automate(vec![ramp], Quantize::Bar)).unwrap();
+        rendered.extend(render.bars(3));
+        golden("tempo_ramp", &rendered);
+    }
+
+    #[test]
+    fn tempo_lane() {
+        let (mut render, clock) = render(100.0);
+        clock.send(notes("pulse", "c4*8")).unwrap();
+        let lane = vec![
+            TempoPoint { beat: Time::from_integer(4), tempo: 200.0, curve: Curve::Exponential },
+            TempoPoint { beat: Time::from_integer(6), tempo: 90.0, curve: Curve::Step },
+            TempoPoint { beat: Time::new(15, 2), tempo: 120.0, curve: Curve::Linear },
+        ];
+        clock.send(ClockCommand::Automate(lane, Quantize::Now)).unwrap();
+        golden("tempo_lane", &render.bars(3));
+    }
+
+    #[test]
+    fn dirt_ramp() {
+        let (mut render, clock) = render(120.0);
+        let hats = (0..4).map(|beat| {
+            Event::new(Time::from_integer(beat), Time::from_integer(beat + 1), BaseEventType::Dirt, Vec::new())
+                .with_param("s", Param::Str("hh".to_string()))
+        }).collect();
+        clock.send(ClockCommand::AddStream(StreamSpec {
+            name: "hats".to_string(),
+            content: StreamContent::Events(hats),
+            quantize: Quantize::Now,
+        })).unwrap();
+        let ramp = TempoPoint { beat: Time::from_integer(4), tempo: 60.0, curve: Curve::Linear };
+        clock.send(ClockCommand::Automate(vec![ramp], Quantize::Now)).unwrap();
+        golden("dirt_ramp", &render.bars(2));
+    }
+
+    #[test]
+    fn timers() {
+        let (mut render, clock) = render(120.0);
+        render.bars(0);
+        define(&render, r#"function(beat)
+            return {{note = 60 + beat % 4, ["end"] = 1/2, channel = 2}}
+        end"#, |lua, callback| timers::register(lua, 1, callback));
+        clock.send(ClockCommand::AddTimer(1, Period::Beats(Time::from_integer(1)))).unwrap();
+        // Returning a tempo on the third bar, and a bare number otherwise.
+        define(&render, r#"function(beat)
+            if beat == 8 then return {tempo = 90} end
+            return beat
+        end"#, |lua, callback| timers::register(lua, 2, callback));
+        clock.send(ClockCommand::AddTimer(2, Period::Bar)).unwrap();
+        golden("timers", &render.bars(4));
+    }
+
+    #[test]
+    fn generated_stream() {
+        let (mut render, clock) = render(120.0);
+        render.bars(0);
+        define(&render, r#"function(cycle, tempo, state)
+            state.count = (state.count or 0) + 1
+            local events = {}
+            for i = 0, cycle % 3 do
+                events[#events + 1] = {begin = i, ["end"] = i + 1/4, note = 48 + state.count + i}
+            end
+            return events
+        end"#, |lua, generator| timers::register_generator(lua, "arp", generator));
+        clock.send(ClockCommand::AddGenerator("arp".to_string())).unwrap();
+        golden("generated_stream", &render.bars(4));
+    }
+
+    #[test]
+    fn routines() {
+        let (mut render, clock) = render(100.0);
+        render.bars(0);
+        spawn(&render, &clock, 1, r#"function()
+            sync("bar")
+            for i = 1, 6 do
+                note(60 + i, 100, 1/2)
+                wait(i % 2 == 0 and 1 or 1/2)
+            end
+            emit({type = "dirt", s = "cp"})
+            wait("bar")
+            note(72, 80, 1)
+        end"#);
+        golden("routines", &render.bars(4));
+    }
+
+    #[test]
+    fn routines_wait_less_than_a_tick() {
+        // A sixteenth at 200 bpm is shorter than a clock tick.
+        let (mut render, clock) = render(200.0);
+        render.bars(0);
+        spawn(&render, &clock, 1, r#"function()
+            sync("1/16")
+            while true do note(60, 100, 1/32); wait(1/16) end
+        end"#);
+        let starts: Vec<i64> = render.bars(2).iter()
+            .filter(|rendered| !is_note_off(&rendered.message))
+            .map(|rendered| rendered.micros)
+            .collect();
+        let expected: Vec<i64> = (starts[0]..2_400_000).step_by(18_750).collect();
+        assert_eq!(starts, expected);
+    }
+
+    #[test]
+    fn routine_notes_longer_than_a_bar_play_once() {
+        let (mut render, clock) = render(120.0);
+        render.bars(0);
+        spawn(&render, &clock, 1, r#"function() sync("bar"); note(60, 100, 6) end"#);
+        let rendered = render.bars(4);
+        let messages: Vec<String> = rendered.iter().map(|rendered| rendered.message.to_string()).collect();
+        assert_eq!(messages, ["midi default NoteOn(60, 100, 0)", "midi default NoteOff(60, 0, 0)"]);
+        assert_eq!(rendered[1].micros - rendered[0].micros, 3_000_000);
+    }
+
+    #[test]
+    fn the_step_does_not_matter() {
+        let play = |step: i64| {
+            let (render, clock) = render(133.0);
+            let mut render = render.with_step(step);
+            clock.send(notes("a", "c4 [d4 e4 f4] ~ g4*5")).unwrap();
+            clock.send(notes("b", "[<c3 g2>, {e3 g3 b3}%4]")).unwrap();
+            text(&render.bars(4))
+        };
+        let expected = play(1000);
+        for step in [3_000, 20_000, 77_777] {
+            assert_eq!(play(step), expected, "step of {}us", step);
+        }
+    }
+
+    #[test]
+    fn the_step_does_not_matter_during_ramps() {
+        let play = |step: i64| {
+            let (render, clock) = render(133.0);
+            let mut render = render.with_step(step);
+            clock.send(notes("a", "c4 [d4 e4 f4] ~ g4*5")).unwrap();
+            clock.send(notes("b", "[<c3 g2>, {e3 g3 b3}%4]")).unwrap();
+            let ramp = TempoPoint { beat: Time::from_integer(6), tempo: 71.0, curve: Curve::Exponential };
+            clock.send(ClockCommand::Automate(vec![ramp], Quantize::Beat)).unwrap();
+            let mut rendered = render.bars(1);
+            rendered.extend(render.until(5.0));
+            // Replaced halfway through, from beat 6.
+            let ramp = TempoPoint { beat: Time::from_integer(2), tempo: 150.0, curve: Curve::Linear };
+            clock.send(ClockCommand::Automate(vec![ramp], Quantize::Beat)).unwrap();
+            rendered.extend(render.bars(4));
+            rendered
+        };
+        let expected = play(1000);
+        for step in [3_000, 20_000, 77_777] {
+            let rendered = play(step);
+            assert_eq!(rendered.len(), expected.len(), "step of {}us", step);
+            for (rendered, expected) in rendered.iter().zip(&expected) {
+                // Ramps are applied at whole microseconds.
+                assert!((rendered.micros - expected.micros).abs() <= 2, "step of {}us: {} != {}", step, rendered, expected);
+                assert_eq!(rendered.message.to_string(), expected.message.to_string());
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use mlua::prelude::*;
+use std::sync::mpsc::Sender;
+
+use crate::clock::ClockCommand;
+use crate::streams::{Event, StreamContent, Time, to_f64};
+use crate::timers::{self, Fired, Period, Wait};
+
+/// Registry table holding the coroutines of the routines, by id.
+const ROUTINES: &str = "eremit.routines";
+
+/// `wait` and `sync` yield to the Rust side, which hands the routine back
+/// to the clock. Outside of a routine, `sync()` still toggles Link
+/// start/stop sync.
+const PRELUDE: &str = r#"
+function wait(beats)
+    return coroutine.yield("wait", beats)
+end
+
+local link_sync = sync
+function sync(period)
+    local routine, main = coroutine.running()
+    if routine == nil or main then
+        return link_sync()
+    end
+    return coroutine.yield("sync", period or "bar")
+end
+
+function note(note, velocity, duration, channel)
+    emit({note = note, velocity = velocity, ["end"] = duration, channel = channel})
+end
+"#;
+
+/// What a routine played since it was resumed, all of it at the beat it was
+/// resumed for. Only present while a routine runs.
+#[derive(Default)]
+struct Played(Vec<StreamContent>);
+
+/// Define `emit`, `note`, `wait` and `sync`, the latter wrapping the
+/// `sync` already defined. `emit` rather than `play`, which starts and
+/// stops the transport.
+pub fn install(lua: &Lua) -> LuaResult<()> {
+    let emit = lua.create_function(|lua, value: LuaValue| {
+        let content = match value {
+            // A single event rather than a list of them.
+            LuaValue::Table(ref table) if !table.contains_key(1)? => {
+                StreamContent::Events(vec![Event::from_lua(value, lua)?])
+            },
+            value => StreamContent::from_lua(value, lua)?,
+        };
+        match lua.app_data_mut::<Played>() {
+            Some(mut played) => {
+                played.0.push(content);
+                Ok(())
+            },
+            None => Err(LuaError::RuntimeError("emit() only works in a routine started by spawn()".to_string())),
+        }
+    })?;
+    lua.globals().set("emit", emit)?;
+    lua.load(PRELUDE).set_name("=routines").exec()
+}
+
+/// Keep `function` as routine `id`, to be started by the clock.
+pub fn spawn(lua: &Lua, id: u64, function: LuaFunction) -> LuaResult<()> {
+    let routine = lua.create_thread(function)?;
+    timers::registry_table(lua, ROUTINES)?.set(id, routine)
+}
+
+pub fn cancel(lua: &Lua, id: u64) -> LuaResult<()> {
+    timers::registry_table(lua, ROUTINES)?.set(id, LuaValue::Nil)
+}
+
+/// Resume routine `id` at `beat`, and again for every wait ending before
+/// `until`, sending what it played to the clock, then tell the clock what
+/// it waits for next. Routines that end or fail are forgotten.
+pub fn resume(
+    lua: &Lua,
+    id: u64,
+    mut beat: Time,
+    until: Time,
+    quantum: f64,
+    clock: Sender<ClockCommand>
+) -> LuaResult<()> {
+    let routines = timers::registry_table(lua, ROUTINES)?;
+    // Cancelled since the clock woke it up.
+    let Some(routine) = routines.get::<_, Option<LuaThread>>(id)? else {
+        return Ok(());
+    };
+    loop {
+        lua.set_app_data(Played::default());
+        let result = routine.resume::<_, LuaMultiValue>(to_f64(beat));
+        let played = lua.remove_app_data::<Played>().unwrap_or_default();
+        for content in played.0 {
+            let length = match &content {
+                StreamContent::Events(events) => events.iter().map(Event::end).max(),
+                StreamContent::Pattern(_) => None,
+            };
+            let fired = Fired { content: Some(content), tempo: None };
+            let _ = clock.send(ClockCommand::Fired(beat, length, fired));
+        }
+        let wait = match result {
+            Ok(_) if routine.status() != LuaThreadStatus::Resumable => None,
+            Ok(values) => Some(yielded(lua, values)),
+            Err(err) => Some(Err(err)),
+        };
+        match wait {
+            // Waits shorter than a clock tick are over before the clock
+            // could wake it up again.
+            Some(Ok(wait)) if wait.until(beat, quantum) < until => {
+                beat = wait.until(beat, quantum);
+            },
+            Some(Ok(wait)) => {
+                let _ = clock.send(ClockCommand::Resume(id, beat, wait));
+                return Ok(());
+            },
+            Some(Err(err)) => {
+                routines.set(id, LuaValue::Nil)?;
+                return Err(LuaError::RuntimeError(format!("routine {}: {}", id, err)));
+            },
+            None => return routines.set(id, LuaValue::Nil),
+        }
+    }
+}
+
+/// What a routine yielded: `"wait", beats` or `"sync", period`.
+fn yielded<'lua>(lua: &'lua Lua, values: LuaMultiValue<'lua>) -> LuaResult<Wait> {
+    let mut values = values.into_iter();
+    let kind = values.next().unwrap_or(LuaValue::Nil);
+    let period = Period::from_lua(values.next().unwrap_or(LuaValue::Nil), lua);
+    match kind.as_str() {
+        Some("wait") => Ok(Wait::For(period?)),
+        Some("sync") => Ok(Wait::Sync(period?)),
+        _ => Err(LuaError::RuntimeError("routines can only yield through wait() and sync()".to_string())),
+    }
+}
@@ -0,0 +1,196 @@
+use num::Zero;
+use std::sync::mpsc::Sender;
+use crate::dispatcher::{DispatcherMessage, OutputMessage};
+use crate::midi::{MidiMessage, MidiTarget};
+use crate::midi::clock_output::ClockOutput;
+use crate::osc;
+use crate::streams::{EventEdge, Occurrence, Stream, Time, to_f64, to_time};
+use crate::tempo::TempoMap;
+
+/// Lookahead scheduler driven by the clock thread. Each tick covers the beat
+/// window between the end of the previous window and the current beat plus
+/// the lookahead, so no event is ever queried twice or skipped. Everything
+/// found is handed to the dispatcher ahead of time with its host timestamp.
+pub struct Scheduler {
+    lookahead: f64,
+    horizon: Option<Time>,
+    /// Edges played from outside the streams, waiting for their window.
+    pending: Vec<Occurrence>,
+    dispatcher: Sender<DispatcherMessage>,
+    /// MIDI clock sent along the events, when enabled.
+    clock_output: Option<ClockOutput>
+}
+
+impl Scheduler {
+    /// `lookahead` is expressed in milliseconds.
+    pub fn new(lookahead: f64, dispatcher: Sender<DispatcherMessage>) -> Self {
+        Self {
+            lookahead,
+            horizon: None,
+            pending: Vec::new(),
+            dispatcher,
+            clock_output: None
+        }
+    }
+
+    pub fn lookahead(&self) -> f64 {
+        self.lookahead
+    }
+
+    pub fn set_lookahead(&mut self, lookahead: f64) {
+        self.lookahead = lookahead.max(0.0);
+    }
+
+    pub fn midi_clock(&self) -> bool {
+        self.clock_output.is_some()
+    }
+
+    /// Start or stop sending MIDI clock. When enabled while playing, receivers
+    /// are told where to resume on the next sixteenth note.
+    pub fn set_midi_clock(&mut self, enabled: bool) {
+        match (enabled, self.clock_output.take()) {
+            (true, output) => self.clock_output = Some(output.unwrap_or_default()),
+            (false, Some(mut output)) => self.stop_midi_clock(&mut output),
+            (false, None) => {}
+        }
+    }
+
+    fn stop_midi_clock(&self, output: &mut ClockOutput) {
+        if output.stop() {
+            let _ = self.dispatcher.send(DispatcherMessage::Now(OutputMessage::Midi(MidiTarget::All, MidiMessage::MidiStop)));
+        }
+    }
+
+    /// The beat up to which events have already been sent, if any.
+    pub fn horizon(&self) -> Option<Time> {
+        self.horizon
+    }
+
+    /// Forget the current window, the next tick starts from the current beat.
+    pub fn reset(&mut self) {
+        self.horizon = None;
+    }
+
+    /// Drop what is still waiting to be sent and silence every held note.
+    /// Used when the transport stops.
+    pub fn release(&mut self) {
+        self.reset();
+        self.pending.clear();
+        let _ = self.dispatcher.send(DispatcherMessage::Clear);
+        let _ = self.dispatcher.send(DispatcherMessage::ReleaseNotes);
+        if let Some(mut output) = self.clock_output.take() {
+            self.stop_midi_clock(&mut output);
+            self.clock_output = Some(output);
+        }
+    }
+
+    /// The beat window to query for this tick. Windows are exact fractions
+    /// of a beat, each one starting precisely where the previous one ended.
+    /// Nothing is played before beat 0, as during a Link count-in.
+    pub fn window(&mut self, beat: f64, tempo: f64) -> (Time, Time) {
+        let lookahead_beats = self.lookahead / 1000.0 * tempo / 60.0;
+        let end = to_time(beat + lookahead_beats);
+        let start = to_time(beat).max(Time::zero());
+        let begin = match self.horizon {
+            // The timeline jumped (Link relocation or a stalled thread), start over.
+            Some(horizon) if (to_f64(horizon) - beat).abs() > 1.0 + lookahead_beats => start,
+            Some(horizon) => horizon,
+            None => start,
+        };
+        let end = end.max(begin);
+        self.horizon = Some(end);
+        (begin, end)
+    }
+
+    /// Query every stream for the current window and send what it returns
+    /// to the dispatcher. Streams apply their pending swaps on the way.
+    pub fn schedule(&mut self,
+        streams: &mut [Stream],
+        map: &TempoMap,
+        beat: f64,
+        quantum: f64
+    ) {
+        let (begin, end) = self.window(beat, map.tempo());
+        let mut occurrences: Vec<Occurrence> = streams.iter_mut()
+            .flat_map(|stream| stream.notify_tick(quantum, begin, end))
+            .collect();
+        let (due, pending): (Vec<_>, _) = std::mem::take(&mut self.pending).into_iter()
+            .partition(|occurrence| occurrence.beat < to_f64(end));
+        self.pending = pending;
+        occurrences.extend(due);
+        self.dispatch(occurrences, map, quantum);
+        if let Some(output) = self.clock_output.as_mut() {
+            for (beat, message) in output.messages(begin, end) {
+                let time = map.time_at_beat(to_f64(beat));
+                let _ = self.dispatcher.send(
+                    DispatcherMessage::Schedule(time, OutputMessage::Midi(MidiTarget::All, message))
+                );
+            }
+        }
+    }
+
+    /// Send the edges falling before the horizon now, and keep the others
+    /// for their own window, timed with the tempo it has by then.
+    pub fn play(&mut self, occurrences: Vec<Occurrence>, map: &TempoMap, quantum: f64) {
+        let horizon = self.horizon.map_or(f64::NEG_INFINITY, to_f64);
+        let (now, later): (_, Vec<_>) = occurrences.into_iter().partition(|occurrence| occurrence.beat < horizon);
+        self.pending.extend(later);
+        self.dispatch(now, map, quantum);
+    }
+
+    /// Send event edges to the dispatcher, whatever the current window.
+    fn dispatch(&self,
+        mut occurrences: Vec<Occurrence>,
+        map: &TempoMap,
+        quantum: f64
+    ) {
+        // Ends are sent before starts so that repeated notes retrigger.
+        occurrences.sort_by(|a, b| {
+            a.beat.total_cmp(&b.beat).then_with(|| {
+                (a.edge == EventEdge::Start).cmp(&(b.edge == EventEdge::Start))
+            })
+        });
+        for occurrence in occurrences {
+            let time = map.time_at_beat(occurrence.beat);
+            if occurrence.event.is_osc() {
+                if occurrence.edge != EventEdge::Start {
+                    continue;
+                }
+                let tempo = map.tempo_at_beat(occurrence.beat);
+                let seconds = (map.time_at_beat(to_f64(occurrence.event.end())) - time) as f64 / 1_000_000.0;
+                if let Some(message) = osc::event_message(&occurrence.event, tempo, seconds, quantum) {
+                    let target = occurrence.event.output().map(str::to_string);
+                    let _ = self.dispatcher.send(
+                        DispatcherMessage::Schedule(time, OutputMessage::Osc(target, message))
+                    );
+                }
+                continue;
+            }
+            let target = MidiTarget::from_alias(occurrence.event.output());
+            for message in occurrence.event.messages(occurrence.edge) {
+                let _ = self.dispatcher.send(
+                    DispatcherMessage::Schedule(time, OutputMessage::Midi(target.clone(), message))
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn nothing_before_the_downbeat() {
+        let (dispatcher, _output) = mpsc::channel();
+        // 100ms ahead at 60 bpm: a tenth of a beat.
+        let mut scheduler = Scheduler::new(100.0, dispatcher);
+        let (begin, end) = scheduler.window(-2.0, 60.0);
+        assert_eq!((begin, end), (Time::zero(), Time::zero()));
+        let (begin, end) = scheduler.window(-0.05, 60.0);
+        assert_eq!((begin, end), (Time::zero(), Time::new(1, 20)));
+        let (begin, end) = scheduler.window(0.0, 60.0);
+        assert_eq!((begin, end), (Time::new(1, 20), Time::new(1, 10)));
+    }
+}
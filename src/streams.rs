@@ -1,11 +1,18 @@
 use core::fmt::Formatter;
 use std::fmt::Display;
-use std::sync::{Arc, Mutex};
-use midir::MidiOutputConnection;
 use std::fmt::Debug;
+use mlua::prelude::*;
 
-use crate::midi::MidiConnexion;
-use crate::midi::MidiMessage;
+use num::{One, Zero};
+
+use crate::midi::{MidiMessage, PITCH_BEND_CENTER, codec};
+use crate::mininotation::{Notation, note_number};
+
+mod pattern;
+pub use pattern::{Hap, Pattern, Span, Time, to_f64, to_time};
+
+/// Denominator of the instant used to look for events sounding at a beat.
+const TIME_EPSILON: i128 = 1_000_000_000;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum BaseEventType {
@@ -19,15 +26,85 @@ pub enum BaseEventType {
     PolyAftertouch,
     SysEx,
     SysCommon,
-    SysRealtime
+    SysRealtime,
+    /// A non-registered parameter, set through control changes.
+    Nrpn,
+    /// A registered parameter, set through control changes.
+    Rpn,
+    /// An OSC message sent to the given address.
+    Osc(String),
+    /// A SuperDirt `/dirt/play` message.
+    Dirt
 }
 
+impl BaseEventType {
+    /// Parse the `type` field of an event defined in Lua.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "tick" => Some(BaseEventType::Tick),
+            "note" | "note_on" => Some(BaseEventType::NoteOn),
+            "note_off" => Some(BaseEventType::NoteOff),
+            "cc" | "control_change" => Some(BaseEventType::ControlChange),
+            "program" | "program_change" => Some(BaseEventType::ProgramChange),
+            "bend" | "pitch_bend" => Some(BaseEventType::PitchBend),
+            "aftertouch" | "channel_pressure" => Some(BaseEventType::Aftertouch),
+            "poly_aftertouch" | "poly_pressure" => Some(BaseEventType::PolyAftertouch),
+            "sysex" => Some(BaseEventType::SysEx),
+            "sys_common" => Some(BaseEventType::SysCommon),
+            "sys_realtime" => Some(BaseEventType::SysRealtime),
+            "nrpn" => Some(BaseEventType::Nrpn),
+            "rpn" => Some(BaseEventType::Rpn),
+            "osc" => Some(BaseEventType::Osc("/eremit".to_string())),
+            "dirt" => Some(BaseEventType::Dirt),
+            _ => None
+        }
+    }
+}
+
+/// The value of an event parameter, sent as an OSC argument. Integers that
+/// do not fit 32 bits are kept as 64 bits.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Param {
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Str(String)
+}
+
+impl<'lua> FromLua<'lua> for Param {
+    fn from_lua(value: LuaValue<'lua>, _lua: &'lua Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Integer(value) => Ok(i32::try_from(value)
+                .map(Param::Int)
+                .unwrap_or(Param::Long(value))),
+            LuaValue::Number(value) => Ok(Param::Float(value as f32)),
+            LuaValue::String(value) => Ok(Param::Str(value.to_str()?.to_string())),
+            LuaValue::Boolean(value) => Ok(Param::Int(value as i32)),
+            other => Err(LuaError::FromLuaConversionError {
+                from: other.type_name(),
+                to: "Param",
+                message: Some("parameters are numbers or strings".to_string())
+            })
+        }
+    }
+}
+
+/// Something happening between two beats. `event_data` holds the
+/// payload of the event type: `[note, velocity]` for notes, `[control, value]`
+/// for control changes, `[lsb, msb]` for pitch bends, the parameter number
+/// then the value, both as `[lsb, msb]`, for (N)RPNs, the payload of system
+/// exclusive messages, the raw bytes of system common and realtime ones, etc.
 #[derive(Debug, PartialEq, Clone)]
 pub struct Event {
-    begin: f64,
-    end: f64,
+    begin: Time,
+    end: Time,
     event_type: BaseEventType,
-    event_data: Vec<u8>
+    channel: u8,
+    event_data: Vec<u8>,
+    /// Alias of the MIDI output or OSC target, the default one when `None`.
+    output: Option<String>,
+    /// Named parameters of OSC events.
+    params: Vec<(String, Param)>
 }
 
 impl Display for Event {
@@ -49,7 +126,11 @@ impl Display for BaseEventType {
             BaseEventType::PolyAftertouch => write!(f, "PolyAftertouch"),
             BaseEventType::SysEx => write!(f, "SysEx"),
             BaseEventType::SysCommon => write!(f, "SysCommon"),
-            BaseEventType::SysRealtime => write!(f, "SysRealtime")
+            BaseEventType::SysRealtime => write!(f, "SysRealtime"),
+            BaseEventType::Nrpn => write!(f, "Nrpn"),
+            BaseEventType::Rpn => write!(f, "Rpn"),
+            BaseEventType::Osc(address) => write!(f, "Osc {}", address),
+            BaseEventType::Dirt => write!(f, "Dirt")
         }
     }
 }
@@ -57,90 +138,617 @@ impl Display for BaseEventType {
 
 impl Event {
 
-    pub fn new(begin: f64, end: f64, event_type: BaseEventType, event_data: Vec<u8>) -> Self {
+    pub fn new(begin: Time, end: Time, event_type: BaseEventType, event_data: Vec<u8>) -> Self {
         Self {
             begin,
             end,
             event_type,
-            event_data
+            channel: 0,
+            event_data,
+            output: None,
+            params: Vec::new()
+        }
+    }
+
+    pub fn with_channel(mut self, channel: u8) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    pub fn with_output(mut self, output: Option<String>) -> Self {
+        self.output = output;
+        self
+    }
+
+    pub fn output(&self) -> Option<&str> {
+        self.output.as_deref()
+    }
+
+    pub fn event_type(&self) -> &BaseEventType {
+        &self.event_type
+    }
+
+    /// Beats the event lasts.
+    pub fn duration(&self) -> Time {
+        self.end - self.begin
+    }
+
+    pub fn begin(&self) -> Time {
+        self.begin
+    }
+
+    pub fn end(&self) -> Time {
+        self.end
+    }
+
+    /// Set a parameter, replacing any parameter with the same name.
+    pub fn with_param(mut self, key: &str, value: Param) -> Self {
+        match self.params.iter_mut().find(|(k, _)| k == key) {
+            Some(param) => param.1 = value,
+            None => self.params.push((key.to_string(), value)),
         }
+        self
+    }
+
+    pub fn params(&self) -> &[(String, Param)] {
+        &self.params
     }
 
-    fn start_event(&self, beat: f64, midi: Arc<Mutex<MidiConnexion>>) {
+    /// Whether the event leaves through OSC rather than MIDI.
+    pub fn is_osc(&self) -> bool {
+        matches!(self.event_type, BaseEventType::Osc(_) | BaseEventType::Dirt)
+    }
+
+    fn data(&self, index: usize, default: u8) -> u8 {
+        self.event_data.get(index).copied().unwrap_or(default)
+    }
+
+    /// The 14-bit value stored as `[lsb, msb]` from `index` on.
+    fn word(&self, index: usize) -> u16 {
+        self.data(index, 0) as u16 | (self.data(index + 1, 0) as u16) << 7
+    }
+
+    /// The 14-bit value of a pitch bend. A single data byte is taken as the
+    /// most significant one.
+    fn bend(&self) -> u16 {
+        match self.event_data.len() {
+            0 => PITCH_BEND_CENTER,
+            1 => (self.data(0, 64) as u16) << 7,
+            _ => self.data(0, 0) as u16 | (self.data(1, 64) as u16) << 7,
+        }
+    }
+
+    /// The messages to send when the event starts.
+    pub fn start_event(&self) -> Vec<MidiMessage> {
+        let channel = self.channel;
         match self.event_type {
-            BaseEventType::Tick => {
-                println!("[Tick] Start event: {}", beat);
-                // Send a C4 note on 
-                midi.lock().unwrap().send(
-                    MidiMessage::NoteOn(60, 120, 0)
-                );
+            BaseEventType::Tick => vec![MidiMessage::NoteOn(60, 120, channel)],
+            BaseEventType::NoteOn => vec![
+                MidiMessage::NoteOn(self.data(0, 60), self.data(1, 100), channel)
+            ],
+            BaseEventType::NoteOff => vec![
+                MidiMessage::NoteOff(self.data(0, 60), self.data(1, 0), channel)
+            ],
+            BaseEventType::ControlChange => vec![
+                MidiMessage::ControlChange(self.data(0, 0), self.data(1, 0), channel)
+            ],
+            BaseEventType::ProgramChange => vec![MidiMessage::ProgramChange(self.data(0, 0), channel)],
+            BaseEventType::PitchBend => vec![MidiMessage::PitchBend(self.bend(), channel)],
+            BaseEventType::Aftertouch => vec![MidiMessage::ChannelPressure(self.data(0, 0), channel)],
+            BaseEventType::PolyAftertouch => vec![
+                MidiMessage::PolyPressure(self.data(0, 60), self.data(1, 0), channel)
+            ],
+            BaseEventType::Nrpn => codec::nrpn(self.word(0), self.word(2), channel),
+            BaseEventType::Rpn => codec::rpn(self.word(0), self.word(2), channel),
+            BaseEventType::SysEx | BaseEventType::SysCommon | BaseEventType::SysRealtime => {
+                let message = match self.event_type {
+                    BaseEventType::SysEx => MidiMessage::sysex(self.event_data.clone()),
+                    _ => MidiMessage::decode(&self.event_data)
+                };
+                match message {
+                    Ok(message) => vec![message],
+                    Err(err) => {
+                        println!("Invalid {} event: {}", self.event_type, err);
+                        Vec::new()
+                    }
+                }
             },
-            _ => {
-                println!("Unknown event type: {}", self.event_type);
-            }
+            BaseEventType::Osc(_) | BaseEventType::Dirt => Vec::new()
         }
     }
 
-    fn end_event(&self, beat: f64, midi: Arc<Mutex<MidiConnexion>>) {
+    /// The messages to send when the event ends.
+    pub fn end_event(&self) -> Vec<MidiMessage> {
         match self.event_type {
-            BaseEventType::Tick => {
-                println!("[Tick] End Event: {}", beat);
-                midi.lock().unwrap().send(
-                    MidiMessage::NoteOff(60, 0)
-                );
+            BaseEventType::Tick => vec![MidiMessage::NoteOff(60, 0, self.channel)],
+            BaseEventType::NoteOn => vec![MidiMessage::NoteOff(self.data(0, 60), 0, self.channel)],
+            _ => Vec::new()
+        }
+    }
+
+    /// The messages to send at one of the edges of the event.
+    pub fn messages(&self, edge: EventEdge) -> Vec<MidiMessage> {
+        match edge {
+            EventEdge::Start => self.start_event(),
+            EventEdge::End => self.end_event(),
+        }
+    }
+}
+
+/// A MIDI value given from Lua, `name` being used in the error when it
+/// does not fit in `0..=max` rather than wrapping it.
+pub fn midi_value(name: &str, value: i64, max: u16) -> LuaResult<u16> {
+    u16::try_from(value)
+        .ok()
+        .filter(|value| *value <= max)
+        .ok_or_else(|| LuaError::RuntimeError(format!("{} out of range (0-{}): {}", name, max, value)))
+}
+
+/// A channel (0-15) or data byte (0-127) given from Lua.
+pub fn midi_byte(name: &str, value: i64, max: u8) -> LuaResult<u8> {
+    midi_value(name, value, max as u16).map(|value| value as u8)
+}
+
+/// Fields of event tables that are not OSC parameters.
+const RESERVED_FIELDS: [&str; 6] = ["begin", "end", "type", "channel", "out", "address"];
+
+/// Events are written in Lua as tables: `{begin = 0, ["end"] = 0.5, note = 60}`.
+/// `type` defaults to a note, `end` to one beat after `begin`. Pitch bends
+/// take a 14-bit `bend` value, (N)RPNs a 14-bit `param` number and `value`,
+/// raw payloads for other event types are given as a `data` list of bytes. `out` is the alias of the MIDI output to use.
+/// OSC events (`type = "osc"` with an `address`, or `type = "dirt"`) take
+/// every other field as a parameter: `{type = "dirt", s = "bd", n = 3}`.
+impl<'lua> FromLua<'lua> for Event {
+    fn from_lua(value: LuaValue<'lua>, lua: &'lua Lua) -> LuaResult<Self> {
+        let table = match value {
+            LuaValue::Table(table) => table,
+            other => return Err(LuaError::FromLuaConversionError {
+                from: other.type_name(),
+                to: "Event",
+                message: Some("events are tables".to_string())
+            })
+        };
+        let invalid = |message: String| LuaError::FromLuaConversionError {
+            from: "table",
+            to: "Event",
+            message: Some(message)
+        };
+        let begin: f64 = table.get::<_, Option<f64>>("begin")?.unwrap_or(0.0);
+        let end: f64 = table.get::<_, Option<f64>>("end")?.unwrap_or(begin + 1.0);
+        if end <= begin {
+            return Err(invalid(format!("event ends ({}) before it begins ({})", end, begin)));
+        }
+        let event_type = match table.get::<_, Option<String>>("type")? {
+            Some(name) => BaseEventType::from_name(&name)
+                .ok_or_else(|| invalid(format!("unknown event type: {}", name)))?,
+            None => BaseEventType::NoteOn
+        };
+        let event_type = match (event_type, table.get::<_, Option<String>>("address")?) {
+            (BaseEventType::Osc(_), Some(address)) => BaseEventType::Osc(address),
+            (event_type, _) => event_type
+        };
+        let byte = |name: &str, default: u8| -> LuaResult<u8> {
+            match table.get::<_, Option<i64>>(name)? {
+                Some(value) => midi_byte(name, value, 0x7F),
+                None => Ok(default)
+            }
+        };
+        let channel = match table.get::<_, Option<i64>>("channel")? {
+            Some(channel) => midi_byte("channel", channel, 0x0F)?,
+            None => 0
+        };
+        let event_data = match table.get::<_, Option<Vec<i64>>>("data")? {
+            // System common and realtime events are given as raw bytes,
+            // status included, and checked when decoded.
+            Some(data) => match event_type {
+                BaseEventType::SysCommon | BaseEventType::SysRealtime => data.into_iter()
+                    .map(|value| midi_byte("data", value, 0xFF))
+                    .collect::<LuaResult<_>>()?,
+                BaseEventType::SysEx => {
+                    let data: Vec<u8> = data.into_iter()
+                        .map(|value| midi_byte("data", value, 0xFF))
+                        .collect::<LuaResult<_>>()?;
+                    MidiMessage::sysex(data.clone())
+                        .map_err(|err| invalid(format!("{}, sysex data go without their F0 and F7 bytes", err)))?;
+                    data
+                },
+                _ => data.into_iter()
+                    .map(|value| midi_byte("data", value, 0x7F))
+                    .collect::<LuaResult<_>>()?
             },
-            _ => {
-                println!("Unknown event type: {}", self.event_type);
+            None => match event_type {
+                BaseEventType::NoteOn | BaseEventType::NoteOff => vec![byte("note", 60)?, byte("velocity", 100)?],
+                BaseEventType::PitchBend => {
+                    let bend = match table.get::<_, Option<i64>>("bend")? {
+                        Some(bend) => midi_value("bend", bend, 0x3FFF)?,
+                        None => PITCH_BEND_CENTER
+                    };
+                    vec![(bend & 0x7F) as u8, (bend >> 7) as u8]
+                },
+                BaseEventType::Nrpn | BaseEventType::Rpn => {
+                    let mut data = Vec::new();
+                    for name in ["param", "value"] {
+                        let word = midi_value(name, table.get::<_, Option<i64>>(name)?.unwrap_or(0), 0x3FFF)?;
+                        data.extend([(word & 0x7F) as u8, (word >> 7) as u8]);
+                    }
+                    data
+                },
+                _ => Vec::new()
+            }
+        };
+        let output = table.get::<_, Option<String>>("out")?;
+        let mut event = Event::new(to_time(begin), to_time(end), event_type, event_data)
+            .with_channel(channel)
+            .with_output(output);
+        if event.is_osc() {
+            for pair in table.pairs::<String, LuaValue>() {
+                let (key, value) = pair?;
+                if !RESERVED_FIELDS.contains(&key.as_str()) {
+                    event = event.with_param(&key, Param::from_lua(value, lua)?);
+                }
             }
         }
+        Ok(event)
     }
 }
 
+/// Which side of an `Event` falls at a given beat.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EventEdge {
+    Start,
+    End
+}
+
+/// An `Event` edge positioned on the absolute Link beat timeline.
+#[derive(Debug, Clone)]
+pub struct Occurrence {
+    pub beat: f64,
+    pub edge: EventEdge,
+    pub event: Event
+}
+
+/// The events of a mini-notation pattern. Words that are note numbers or
+/// note names (`60`, `c4`, `eb3`) become notes, any other word a tick.
+pub fn notation_events(notation: &Notation, channel: u8, velocity: u8) -> Pattern<Event> {
+    notation.pattern().fmap(move |word| {
+        let (event_type, data) = match note_number(&word) {
+            Some(note) => (BaseEventType::NoteOn, vec![note, velocity]),
+            None => (BaseEventType::Tick, Vec::new())
+        };
+        Event::new(Time::zero(), Time::one(), event_type, data).with_channel(channel)
+    })
+}
+
+/// SuperDirt events for a mini-notation pattern: every word is a sample
+/// name, optionally followed by its index (`bd:3`).
+pub fn dirt_events(notation: &Notation, params: Vec<(String, Param)>) -> Pattern<Event> {
+    notation.pattern().fmap(move |word| {
+        let (sound, index) = match word.split_once(':') {
+            Some((sound, index)) => (sound.to_string(), index.parse::<f32>().ok()),
+            None => (word, None)
+        };
+        let mut event = Event::new(Time::zero(), Time::one(), BaseEventType::Dirt, Vec::new())
+            .with_param("s", Param::Str(sound));
+        if let Some(index) = index {
+            event = event.with_param("n", Param::Float(index));
+        }
+        for (key, value) in &params {
+            event = event.with_param(key, value.clone());
+        }
+        event
+    })
+}
+
+fn pattern_argument<'lua>(value: LuaValue<'lua>, lua: &'lua Lua) -> LuaResult<Pattern<Event>> {
+    Pattern::<Event>::from_lua(value, lua)
+}
+
+/// Patterns are exposed to Lua as userdata with chainable combinators:
+/// `pat("bd sn"):fast(2):every(3, function(p) return p:rev() end)`.
+impl LuaUserData for Pattern<Event> {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("fast", |_, this, factor: f64| Ok(this.fast(to_time(factor))));
+        methods.add_method("slow", |_, this, factor: f64| Ok(this.slow(to_time(factor))));
+        methods.add_method("early", |_, this, offset: f64| Ok(this.early(to_time(offset))));
+        methods.add_method("late", |_, this, offset: f64| Ok(this.late(to_time(offset))));
+        methods.add_method("rev", |_, this, ()| Ok(this.rev()));
+        methods.add_method("out", |_, this, output: String| {
+            Ok(this.fmap(move |event| event.with_output(Some(output.clone()))))
+        });
+        methods.add_method("set", |_, this, (key, value): (String, Param)| {
+            Ok(this.fmap(move |event| event.with_param(&key, value.clone())))
+        });
+        methods.add_method("degrade", |_, this, amount: Option<f64>| {
+            Ok(this.degrade_by(amount.unwrap_or(0.5)))
+        });
+        methods.add_method("every", |lua, this, (n, function): (i64, LuaFunction)| {
+            let transformed = pattern_argument(function.call(this.clone())?, lua)?;
+            Ok(this.every(n, transformed))
+        });
+        methods.add_method("off", |lua, this, (offset, function): (f64, Option<LuaFunction>)| {
+            let transformed = match function {
+                Some(function) => pattern_argument(function.call(this.clone())?, lua)?,
+                None => this.clone()
+            };
+            Ok(this.off(to_time(offset), transformed))
+        });
+        methods.add_meta_method(LuaMetaMethod::ToString, |_, _, ()| Ok("pattern"));
+    }
+}
+
+/// Wherever a pattern is expected, Lua can give a pattern or a mini-notation string.
+impl<'lua> FromLua<'lua> for Pattern<Event> {
+    fn from_lua(value: LuaValue<'lua>, _lua: &'lua Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::UserData(data) => Ok(data.borrow::<Pattern<Event>>()?.clone()),
+            LuaValue::String(source) => {
+                let notation = Notation::parse(source.to_str()?).map_err(LuaError::external)?;
+                Ok(notation_events(&notation, 0, 100))
+            },
+            other => Err(LuaError::FromLuaConversionError {
+                from: other.type_name(),
+                to: "Pattern",
+                message: Some("expected a pattern or a mini-notation string".to_string())
+            })
+        }
+    }
+}
+
+/// What a stream plays, as given to `stream()` in Lua: either a table of
+/// events positioned in beats of the bar, or a pattern.
+#[derive(Debug, Clone)]
+pub enum StreamContent {
+    Events(Vec<Event>),
+    Pattern(Pattern<Event>)
+}
+
+impl StreamContent {
+    /// A cycle of the pattern lasts `quantum` beats.
+    pub fn into_pattern(self, quantum: f64) -> Pattern<Event> {
+        self.with_events(quantum, Pattern::at)
+    }
+
+    /// `into_pattern` for content played a single time: events are not
+    /// repeated every cycle, however long they last.
+    pub fn into_single_pass(self, quantum: f64) -> Pattern<Event> {
+        self.with_events(quantum, Pattern::once)
+    }
+
+    fn with_events(self, quantum: f64, place: fn(Event, Time, Time) -> Pattern<Event>) -> Pattern<Event> {
+        match self {
+            StreamContent::Pattern(pattern) => pattern,
+            StreamContent::Events(events) => {
+                let quantum = to_time(quantum);
+                Pattern::stack(events.into_iter()
+                    .map(|event| {
+                        let (begin, end) = (event.begin / quantum, event.end / quantum);
+                        place(event, begin, end)
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for StreamContent {
+    fn from_lua(value: LuaValue<'lua>, lua: &'lua Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Table(_) => Ok(StreamContent::Events(Vec::<Event>::from_lua(value, lua)?)),
+            value => Ok(StreamContent::Pattern(Pattern::<Event>::from_lua(value, lua)?))
+        }
+    }
+}
+
+/// A pattern waiting to replace the one a stream plays, at a given beat.
+/// `None` stops the stream for good.
+#[derive(Clone)]
+struct Swap {
+    beat: Time,
+    pattern: Option<Pattern<Event>>
+}
+
 #[derive(Clone)]
 pub struct Stream {
     name: String,
-    pattern: Vec<Event>,
-    midi: Arc<Mutex<MidiConnexion>>,
-    current_bar: i32
+    pattern: Pattern<Event>,
+    swap: Option<Swap>,
+    finished: bool
 }
 
 impl Stream {
-    pub fn new(name: String, midi: Arc<Mutex<MidiConnexion>>) -> Self {
+    pub fn new(name: String) -> Self {
         Self {
             name,
-            pattern: Vec::new(),
-            midi: midi,
-            current_bar: 1 as i32
+            pattern: Pattern::silence(),
+            swap: None,
+            finished: false
         }
     }
 
-    pub fn add_event(&mut self, event: Event) {
-        self.pattern.push(event);
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
-    pub fn process_events(&mut self, 
-        beat: f64, 
-        _bar: f64, 
-        _quantum: f64
-    ) {
-        if self.current_bar != _bar as i32 {
-            self.current_bar = _bar as i32;
-            for event in self.pattern.iter() {
+    pub fn pattern(&self) -> &Pattern<Event> {
+        &self.pattern
+    }
+
+    /// The pattern the stream will play once pending swaps are applied.
+    pub fn next_pattern(&self) -> Option<&Pattern<Event>> {
+        match &self.swap {
+            Some(swap) => swap.pattern.as_ref(),
+            None => Some(&self.pattern)
+        }
+    }
 
+    /// Whether the stream was removed and has nothing left to play.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Layer more content on top of what the stream already plays.
+    pub fn add_content(&mut self, content: StreamContent, quantum: f64) {
+        self.pattern = Pattern::stack(vec![self.pattern.clone(), content.into_pattern(quantum)]);
+    }
+
+    /// Replace everything the stream plays.
+    pub fn set_content(&mut self, content: StreamContent, quantum: f64) {
+        self.set_pattern(content.into_pattern(quantum));
+    }
+
+    /// Replace the pattern right away, dropping any pending swap.
+    pub fn set_pattern(&mut self, pattern: Pattern<Event>) {
+        self.pattern = pattern;
+        self.swap = None;
+    }
+
+    /// Replace the pattern once the stream reaches `beat`, overriding any
+    /// swap still pending.
+    pub fn swap_at(&mut self, beat: Time, pattern: Pattern<Event>) {
+        self.swap = Some(Swap { beat, pattern: Some(pattern) });
+    }
+
+    /// Stop the stream once it reaches `beat`.
+    pub fn remove_at(&mut self, beat: Time) {
+        self.swap = Some(Swap { beat, pattern: None });
+    }
+
+    fn push_edges(
+        pattern: &Pattern<Event>,
+        span: Span,
+        quantum: Time,
+        occurrences: &mut Vec<Occurrence>
+    ) {
+        for hap in pattern.query(span) {
+            let Some(whole) = hap.whole else {
+                continue
+            };
+            // A whole split across cycles or queries comes in fragments:
+            // only the first one starts it and only the last one ends it.
+            let (starts, ends) = (hap.has_onset(), whole.end == hap.part.end);
+            let mut event = hap.value;
+            event.begin = whole.begin * quantum;
+            event.end = whole.end * quantum;
+            if starts {
+                occurrences.push(Occurrence {
+                    beat: to_f64(event.begin),
+                    edge: EventEdge::Start,
+                    event: event.clone()
+                });
+            }
+            if ends {
+                occurrences.push(Occurrence {
+                    beat: to_f64(event.end),
+                    edge: EventEdge::End,
+                    event
+                });
             }
-        } else {
-            return
         }
     }
 
-    pub fn notify_tick(&mut self, 
-        quantum: f64,
-        beat: f64, 
-        bar: f64,
+    /// Cut the events still sounding at `cycle`, so that no note outlives
+    /// the pattern it belongs to.
+    fn push_dangling_ends(
+        pattern: &Pattern<Event>,
+        cycle: Time,
+        quantum: Time,
+        occurrences: &mut Vec<Occurrence>
     ) {
-        if self.pattern.is_empty() {
-            return
+        let instant = Span::new(cycle, cycle + Time::new(1, TIME_EPSILON));
+        for hap in pattern.query(instant) {
+            let Some(whole) = hap.whole else {
+                continue
+            };
+            if whole.begin < cycle && whole.end > cycle {
+                let mut event = hap.value;
+                event.begin = whole.begin * quantum;
+                event.end = cycle * quantum;
+                occurrences.push(Occurrence {
+                    beat: to_f64(event.end),
+                    edge: EventEdge::End,
+                    event
+                });
+            }
+        }
+    }
+
+    /// The event edges of the beat window `[begin, end)` the scheduler
+    /// queries, a cycle of the pattern lasting `quantum` beats. Starts fall in
+    /// `[begin, end)`. An end is sent along with the last fragment of its
+    /// event, so ends fall in `(begin, end]`: one right at `end` comes with
+    /// this window, not the next. A pending swap falling in the window is
+    /// applied at its exact beat.
+    pub fn process_events(&mut self,
+        begin: Time,
+        end: Time,
+        quantum: f64
+    ) -> Vec<Occurrence> {
+        let mut occurrences = Vec::new();
+        if end <= begin || self.finished {
+            return occurrences
         }
-        self.process_events(beat, bar, quantum);
+        let quantum = to_time(quantum);
+        let mut from = begin;
+        if let Some(swap) = self.swap.take_if(|swap| swap.beat < end) {
+            let at = swap.beat.max(begin);
+            Self::push_edges(&self.pattern, Span::new(begin / quantum, at / quantum), quantum, &mut occurrences);
+            Self::push_dangling_ends(&self.pattern, at / quantum, quantum, &mut occurrences);
+            match swap.pattern {
+                Some(pattern) => self.pattern = pattern,
+                None => {
+                    self.pattern = Pattern::silence();
+                    self.finished = true;
+                    return occurrences
+                }
+            }
+            from = at;
+        }
+        Self::push_edges(&self.pattern, Span::new(from / quantum, end / quantum), quantum, &mut occurrences);
+        occurrences
+    }
+
+    /// The edges of a single pass of `pattern` lasting `length` beats, its
+    /// first cycle starting at `begin`. What still sounds at the end is cut.
+    pub fn once(pattern: &Pattern<Event>, begin: Time, length: Time, quantum: f64) -> Vec<Occurrence> {
+        let quantum = to_time(quantum);
+        let pattern = pattern.late(begin / quantum);
+        let end = (begin + length) / quantum;
+        let mut occurrences = Vec::new();
+        Self::push_edges(&pattern, Span::new(begin / quantum, end), quantum, &mut occurrences);
+        Self::push_dangling_ends(&pattern, end, quantum, &mut occurrences);
+        occurrences
+    }
+
+    pub fn notify_tick(&mut self,
+        quantum: f64,
+        begin: Time,
+        end: Time,
+    ) -> Vec<Occurrence> {
+        self.process_events(begin, end, quantum)
    }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_wholes_play_once() {
+        // Notes lasting two bars, queried cycle by cycle by `every`.
+        let note = Pattern::pure(Event::new(Time::zero(), Time::one(), BaseEventType::NoteOn, vec![60, 100]));
+        let long = note.slow(Time::from_integer(2));
+        let mut stream = Stream::new("long".to_string());
+        stream.set_pattern(long.every(3, long.clone()));
+        let mut edges: Vec<_> = [(0, 6), (6, 16)].into_iter()
+            .flat_map(|(begin, end)| stream.process_events(Time::from_integer(begin), Time::from_integer(end), 4.0))
+            .map(|occurrence| (occurrence.beat, occurrence.edge))
+            .collect();
+        edges.sort_by(|a, b| a.0.total_cmp(&b.0));
+        assert_eq!(edges, vec![
+            (0.0, EventEdge::Start),
+            (8.0, EventEdge::End),
+            (8.0, EventEdge::Start),
+            (16.0, EventEdge::End),
+        ]);
+    }
+}
@@ -0,0 +1,504 @@
+use num::rational::Ratio;
+use num::{ToPrimitive, Zero};
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Pattern time, in cycles. Rational so that long sets never drift, on 128
+/// bits so that the denominators of nested `fast` and `slow` factors, which
+/// multiply, do not overflow.
+pub type Time = Ratio<i128>;
+
+/// Resolution used when converting floating point positions to `Time`.
+const TIME_RESOLUTION: i128 = 1_000_000;
+
+/// Convert a floating point position to `Time`, rounded to a millionth.
+pub fn to_time(value: f64) -> Time {
+    Time::new((value * TIME_RESOLUTION as f64).round() as i128, TIME_RESOLUTION)
+}
+
+pub fn to_f64(time: Time) -> f64 {
+    time.to_f64().unwrap_or(0.0)
+}
+
+/// A span of time, from `begin` (included) to `end` (excluded).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub begin: Time,
+    pub end: Time,
+}
+
+impl Span {
+    pub fn new(begin: Time, end: Time) -> Self {
+        Self { begin, end }
+    }
+
+    pub fn with_time(&self, f: impl Fn(Time) -> Time) -> Self {
+        Self::new(f(self.begin), f(self.end))
+    }
+
+    pub fn intersect(&self, other: &Span) -> Option<Span> {
+        let begin = self.begin.max(other.begin);
+        let end = self.end.min(other.end);
+        (begin < end).then_some(Span::new(begin, end))
+    }
+
+    /// Split the span at cycle boundaries.
+    pub fn cycles(&self) -> Vec<Span> {
+        let mut spans = Vec::new();
+        let mut begin = self.begin;
+        while begin < self.end {
+            let next = begin.floor() + 1;
+            let end = next.min(self.end);
+            spans.push(Span::new(begin, end));
+            begin = end;
+        }
+        spans
+    }
+}
+
+/// A value active during `part`, a fragment of the `whole` event it belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hap<T> {
+    pub whole: Option<Span>,
+    pub part: Span,
+    pub value: T,
+}
+
+impl<T> Hap<T> {
+    pub fn with_time(self, f: impl Fn(Time) -> Time) -> Self {
+        Self {
+            whole: self.whole.map(|whole| whole.with_time(&f)),
+            part: self.part.with_time(&f),
+            value: self.value,
+        }
+    }
+
+    pub fn with_value<U>(self, f: impl Fn(T) -> U) -> Hap<U> {
+        Hap {
+            whole: self.whole,
+            part: self.part,
+            value: f(self.value),
+        }
+    }
+
+    /// Whether this fragment contains the beginning of the whole event.
+    pub fn has_onset(&self) -> bool {
+        self.whole.is_some_and(|whole| whole.begin == self.part.begin)
+    }
+}
+
+type Query<T> = dyn Fn(Span) -> Vec<Hap<T>> + Send + Sync;
+
+/// A function from a span of time to the haps happening during that span.
+/// Patterns are cheap to clone and can be sent to the clock thread.
+pub struct Pattern<T> {
+    query: Arc<Query<T>>,
+}
+
+impl<T> Clone for Pattern<T> {
+    fn clone(&self) -> Self {
+        Self { query: self.query.clone() }
+    }
+}
+
+impl<T> Debug for Pattern<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Pattern")
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Pattern<T> {
+    pub fn new(query: impl Fn(Span) -> Vec<Hap<T>> + Send + Sync + 'static) -> Self {
+        Self { query: Arc::new(query) }
+    }
+
+    pub fn query(&self, span: Span) -> Vec<Hap<T>> {
+        if span.begin >= span.end {
+            return Vec::new();
+        }
+        (self.query)(span)
+    }
+
+    pub fn silence() -> Self {
+        Self::new(|_| Vec::new())
+    }
+
+    /// The value repeated once per cycle.
+    pub fn pure(value: T) -> Self {
+        Self::new(move |span: Span| {
+            span.cycles().into_iter()
+                .map(|part| {
+                    let begin = part.begin.floor();
+                    Hap {
+                        whole: Some(Span::new(begin, begin + 1)),
+                        part,
+                        value: value.clone(),
+                    }
+                })
+                .collect()
+        })
+    }
+
+    /// The value between `begin` and `end` of every cycle. `end` may go past
+    /// the end of the cycle.
+    pub fn at(value: T, begin: Time, end: Time) -> Self {
+        Self::new(move |span: Span| {
+            let mut haps = Vec::new();
+            let mut cycle = (span.begin - end).floor();
+            while cycle < span.end {
+                let whole = Span::new(cycle + begin, cycle + end);
+                if let Some(part) = whole.intersect(&span) {
+                    haps.push(Hap {
+                        whole: Some(whole),
+                        part,
+                        value: value.clone(),
+                    });
+                }
+                cycle += 1;
+            }
+            haps
+        })
+    }
+
+    /// The value between `begin` and `end`, a single time rather than every
+    /// cycle.
+    pub fn once(value: T, begin: Time, end: Time) -> Self {
+        let whole = Span::new(begin, end);
+        Self::new(move |span: Span| {
+            whole.intersect(&span)
+                .map(|part| Hap { whole: Some(whole), part, value: value.clone() })
+                .into_iter()
+                .collect()
+        })
+    }
+
+    /// Split queries at cycle boundaries before passing them to `query`.
+    fn split_queries(query: impl Fn(Span) -> Vec<Hap<T>> + Send + Sync + 'static) -> Self {
+        Self::new(move |span: Span| {
+            span.cycles().into_iter().flat_map(&query).collect()
+        })
+    }
+
+    pub fn fmap<U: Clone + Send + Sync + 'static>(&self, f: impl Fn(T) -> U + Send + Sync + 'static) -> Pattern<U> {
+        let pattern = self.clone();
+        let f = Arc::new(f);
+        Pattern::new(move |span| {
+            pattern.query(span).into_iter().map(|hap| hap.with_value(|v| f(v))).collect()
+        })
+    }
+
+    /// Keep the haps whose value satisfies `predicate`.
+    pub fn filter_values(&self, predicate: impl Fn(&T) -> bool + Send + Sync + 'static) -> Self {
+        let pattern = self.clone();
+        Self::new(move |span| {
+            pattern.query(span).into_iter().filter(|hap| predicate(&hap.value)).collect()
+        })
+    }
+
+    /// Apply `f` to the time of queries and the inverse `g` to the haps found.
+    fn with_time(&self,
+        f: impl Fn(Time) -> Time + Send + Sync + 'static,
+        g: impl Fn(Time) -> Time + Send + Sync + 'static
+    ) -> Self {
+        let pattern = self.clone();
+        Self::new(move |span: Span| {
+            pattern.query(span.with_time(&f)).into_iter().map(|hap| hap.with_time(&g)).collect()
+        })
+    }
+
+    /// Speed the pattern up by `factor`.
+    pub fn fast(&self, factor: Time) -> Self {
+        if factor <= Time::zero() {
+            return Self::silence();
+        }
+        self.with_time(move |t| t * factor, move |t| t / factor)
+    }
+
+    /// Slow the pattern down by `factor`.
+    pub fn slow(&self, factor: Time) -> Self {
+        if factor <= Time::zero() {
+            return Self::silence();
+        }
+        self.fast(factor.recip())
+    }
+
+    /// Shift the pattern earlier in time.
+    pub fn early(&self, offset: Time) -> Self {
+        self.with_time(move |t| t + offset, move |t| t - offset)
+    }
+
+    /// Shift the pattern later in time.
+    pub fn late(&self, offset: Time) -> Self {
+        self.early(-offset)
+    }
+
+    /// Reverse every cycle.
+    pub fn rev(&self) -> Self {
+        let pattern = self.clone();
+        Self::split_queries(move |span: Span| {
+            let cycle = span.begin.floor();
+            let next = cycle + 1;
+            let reflect = move |t: Time| cycle + (next - t);
+            pattern.query(Span::new(reflect(span.end), reflect(span.begin))).into_iter()
+                .map(|hap| Hap {
+                    whole: hap.whole.map(|w| Span::new(reflect(w.end), reflect(w.begin))),
+                    part: Span::new(reflect(hap.part.end), reflect(hap.part.begin)),
+                    value: hap.value,
+                })
+                .collect()
+        })
+    }
+
+    /// Play the patterns at the same time.
+    pub fn stack(patterns: Vec<Self>) -> Self {
+        Self::new(move |span| {
+            patterns.iter().flat_map(|pattern| pattern.query(span)).collect()
+        })
+    }
+
+    /// One pattern per cycle, in turn. Each pattern only counts the cycles
+    /// in which it plays.
+    pub fn slowcat(patterns: Vec<Self>) -> Self {
+        if patterns.is_empty() {
+            return Self::silence();
+        }
+        Self::split_queries(move |span: Span| {
+            let count = patterns.len() as i128;
+            let cycle = span.begin.floor().to_integer();
+            let pattern = &patterns[cycle.rem_euclid(count) as usize];
+            let shift = Time::from_integer(cycle.div_euclid(count) - cycle);
+            pattern.query(span.with_time(|t| t + shift)).into_iter()
+                .map(|hap| hap.with_time(|t| t - shift))
+                .collect()
+        })
+    }
+
+    /// The patterns squeezed one after the other in a single cycle.
+    pub fn fastcat(patterns: Vec<Self>) -> Self {
+        let count = patterns.len() as i128;
+        Self::slowcat(patterns).fast(Time::from_integer(count))
+    }
+
+    /// The patterns squeezed in a single cycle, each one taking a share of
+    /// the cycle proportional to its weight.
+    pub fn timecat(steps: Vec<(Time, Self)>) -> Self {
+        let total: Time = steps.iter().map(|(weight, _)| *weight).sum();
+        if total <= Time::zero() {
+            return Self::silence();
+        }
+        let mut offset = Time::zero();
+        let mut layers = Vec::new();
+        for (weight, pattern) in steps {
+            let begin = offset / total;
+            let end = (offset + weight) / total;
+            offset += weight;
+            layers.push(pattern.compress(begin, end));
+        }
+        Self::stack(layers)
+    }
+
+    /// Squeeze every cycle of the pattern between `begin` and `end` (both
+    /// between 0 and 1) of the cycle, leaving the rest silent.
+    pub fn compress(&self, begin: Time, end: Time) -> Self {
+        if begin >= end || begin < Time::zero() || end > Time::from_integer(1) {
+            return Self::silence();
+        }
+        let width = end - begin;
+        let pattern = self.clone();
+        Self::split_queries(move |span: Span| {
+            let cycle = span.begin.floor();
+            let Some(span) = span.intersect(&Span::new(cycle + begin, cycle + end)) else {
+                return Vec::new();
+            };
+            let inner = move |t: Time| cycle + (t - cycle - begin) / width;
+            let outer = move |t: Time| cycle + begin + (t - cycle) * width;
+            pattern.query(span.with_time(inner)).into_iter()
+                .map(|hap| hap.with_time(outer))
+                .collect()
+        })
+    }
+
+    /// Apply `transformed` instead of the pattern every `n` cycles,
+    /// starting with the first.
+    pub fn every(&self, n: i64, transformed: Self) -> Self {
+        if n <= 0 {
+            return self.clone();
+        }
+        let pattern = self.clone();
+        Self::split_queries(move |span: Span| {
+            let cycle = span.begin.floor().to_integer();
+            if cycle.rem_euclid(n.into()) == 0 {
+                transformed.query(span)
+            } else {
+                pattern.query(span)
+            }
+        })
+    }
+
+    /// Layer `transformed`, shifted later by `offset`, on top of the pattern.
+    pub fn off(&self, offset: Time, transformed: Self) -> Self {
+        Self::stack(vec![self.clone(), transformed.late(offset)])
+    }
+
+    /// Randomly remove haps, `amount` being the probability of removal.
+    /// Randomness depends on time only, so a cycle always sounds the same.
+    pub fn degrade_by(&self, amount: f64) -> Self {
+        let pattern = self.clone();
+        Self::new(move |span| {
+            pattern.query(span).into_iter()
+                .filter(|hap| {
+                    let time = hap.whole.map_or(hap.part.begin, |whole| whole.begin);
+                    time_to_rand(to_f64(time)) >= amount
+                })
+                .collect()
+        })
+    }
+
+    pub fn degrade(&self) -> Self {
+        self.degrade_by(0.5)
+    }
+}
+
+fn xorwise(x: i32) -> i32 {
+    let a = (x << 13) ^ x;
+    let b = (a >> 17) ^ a;
+    (b << 5) ^ b
+}
+
+/// Pseudo-random number between 0 and 1 for a point in time, as in Tidal.
+pub fn time_to_rand(time: f64) -> f64 {
+    let seed = ((time / 300.0).fract() * 536870912.0).trunc() as i32;
+    xorwise(seed).rem_euclid(536870912) as f64 / 536870912.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(numer: i128, denom: i128) -> Time {
+        Time::new(numer, denom)
+    }
+
+    fn seq(words: &[&'static str]) -> Pattern<&'static str> {
+        Pattern::fastcat(words.iter().map(|word| Pattern::pure(*word)).collect())
+    }
+
+    /// The wholes starting between `begin` and `end` cycles, in order.
+    fn onsets(pattern: &Pattern<&'static str>, begin: i128, end: i128) -> Vec<(Time, Time, &'static str)> {
+        let span = Span::new(Time::from_integer(begin), Time::from_integer(end));
+        let mut onsets: Vec<_> = pattern.query(span).into_iter()
+            .filter(Hap::has_onset)
+            .map(|hap| (hap.whole.unwrap().begin, hap.whole.unwrap().end, hap.value))
+            .collect();
+        onsets.sort_by_key(|onset| onset.0);
+        onsets
+    }
+
+    #[test]
+    fn fast_and_slow() {
+        assert_eq!(onsets(&seq(&["a", "b"]).fast(t(2, 1)), 0, 1), vec![
+            (t(0, 1), t(1, 4), "a"),
+            (t(1, 4), t(1, 2), "b"),
+            (t(1, 2), t(3, 4), "a"),
+            (t(3, 4), t(1, 1), "b"),
+        ]);
+        assert_eq!(onsets(&seq(&["a", "b"]).slow(t(2, 1)), 0, 2), vec![
+            (t(0, 1), t(1, 1), "a"),
+            (t(1, 1), t(2, 1), "b"),
+        ]);
+        assert!(onsets(&seq(&["a"]).fast(t(0, 1)), 0, 1).is_empty());
+    }
+
+    #[test]
+    fn odd_factors_hold_over_long_runs() {
+        // The denominators of the factors multiply past 64 bits.
+        let (fast, slow) = (to_time(1.234567), to_time(1.7654321));
+        let pattern = seq(&["bd", "sn"]).fast(fast).slow(slow);
+        let step = t(1, 2) / fast * slow;
+        for begin in [0, 1_000, 1_000_000, 1_000_000_000] {
+            let onsets = onsets(&pattern, begin, begin + 8);
+            assert!(onsets.len() > 8, "from cycle {}", begin);
+            for pair in onsets.windows(2) {
+                assert_eq!(pair[1].0 - pair[0].0, step, "from cycle {}", begin);
+                assert_ne!(pair[0].2, pair[1].2);
+            }
+            for (onset, end, _) in onsets {
+                assert_eq!(end - onset, step);
+                assert!(onset >= t(begin, 1) && onset < t(begin + 8, 1));
+            }
+        }
+    }
+
+    #[test]
+    fn slow_wholes_span_cycles() {
+        let pattern = Pattern::pure("a").slow(t(2, 1));
+        let first = pattern.query(Span::new(t(0, 1), t(1, 1)));
+        let second = pattern.query(Span::new(t(1, 1), t(2, 1)));
+        assert_eq!(first.len(), 1);
+        assert_eq!(second.len(), 1);
+        assert_eq!(first[0].whole, second[0].whole);
+        assert!(first[0].has_onset());
+        assert!(!second[0].has_onset());
+    }
+
+    #[test]
+    fn rev() {
+        assert_eq!(onsets(&seq(&["a", "b", "c"]).rev(), 1, 2), vec![
+            (t(1, 1), t(4, 3), "c"),
+            (t(4, 3), t(5, 3), "b"),
+            (t(5, 3), t(2, 1), "a"),
+        ]);
+    }
+
+    #[test]
+    fn every() {
+        let pattern = seq(&["a"]).every(3, seq(&["b", "b"]));
+        let values = |cycle| onsets(&pattern, cycle, cycle + 1).into_iter().map(|onset| onset.2).collect::<Vec<_>>();
+        assert_eq!(values(0), vec!["b", "b"]);
+        assert_eq!(values(1), vec!["a"]);
+        assert_eq!(values(2), vec!["a"]);
+        assert_eq!(values(3), vec!["b", "b"]);
+        assert_eq!(values(-3), vec!["b", "b"]);
+    }
+
+    #[test]
+    fn off() {
+        let pattern = seq(&["a"]).off(t(1, 4), seq(&["b"]));
+        assert_eq!(onsets(&pattern, 0, 1), vec![
+            (t(0, 1), t(1, 1), "a"),
+            (t(1, 4), t(5, 4), "b"),
+        ]);
+        // The shifted copy of the previous cycle still sounds at its start.
+        let haps = pattern.query(Span::new(t(0, 1), t(1, 8)));
+        assert!(haps.iter().any(|hap| hap.value == "b" && hap.whole == Some(Span::new(t(-3, 4), t(1, 4)))));
+    }
+
+    #[test]
+    fn degrade() {
+        let pattern = Pattern::pure("a").fast(t(64, 1));
+        let kept = onsets(&pattern.degrade(), 0, 1);
+        assert!(kept.len() > 16 && kept.len() < 48, "{} of 64 kept", kept.len());
+        // The same cycle always loses the same haps.
+        assert_eq!(onsets(&pattern.degrade(), 0, 1), kept);
+        assert_eq!(onsets(&pattern.degrade_by(0.0), 0, 1).len(), 64);
+        assert!(onsets(&pattern.degrade_by(1.0), 0, 1).is_empty());
+    }
+
+    #[test]
+    fn timecat() {
+        let pattern = Pattern::timecat(vec![(t(1, 1), seq(&["a"])), (t(3, 1), seq(&["b", "c"]))]);
+        assert_eq!(onsets(&pattern, 2, 3), vec![
+            (t(2, 1), t(9, 4), "a"),
+            (t(9, 4), t(21, 8), "b"),
+            (t(21, 8), t(3, 1), "c"),
+        ]);
+        assert!(onsets(&Pattern::timecat(Vec::new()), 0, 1).is_empty());
+    }
+
+    #[test]
+    fn slowcat_counts_its_own_cycles() {
+        let pattern = Pattern::slowcat(vec![seq(&["a"]), Pattern::slowcat(vec![seq(&["b"]), seq(&["c"])])]);
+        let values: Vec<_> = onsets(&pattern, 0, 4).into_iter().map(|onset| onset.2).collect();
+        assert_eq!(values, vec!["a", "b", "a", "c"]);
+    }
+}
@@ -0,0 +1,342 @@
+use mlua::prelude::*;
+
+use crate::streams::{Time, to_f64, to_time};
+use crate::time_source::TimeSource;
+
+/// Beats between two tempo changes along a curve, as a fraction.
+const RESOLUTION: (i128, i128) = (1, 16);
+
+/// How the tempo goes from a point of a lane to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// Equal ratios in equal times, which sounds even.
+    Exponential,
+    /// The previous tempo until the point, then a jump.
+    Step,
+}
+
+impl Curve {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "linear" | "lin" => Some(Curve::Linear),
+            "exponential" | "exp" => Some(Curve::Exponential),
+            "step" => Some(Curve::Step),
+            _ => None,
+        }
+    }
+
+    /// The tempo `x` (0 to 1) of the way from `from` to `to`.
+    fn tempo(&self, from: f64, to: f64, x: f64) -> f64 {
+        match self {
+            Curve::Linear => from + (to - from) * x,
+            Curve::Exponential => from * (to / from).powf(x),
+            Curve::Step if x < 1.0 => from,
+            Curve::Step => to,
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for Curve {
+    /// `nil` (linear), `"linear"`, `"exp"` or `"step"`.
+    fn from_lua(value: LuaValue<'lua>, _lua: &'lua Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Nil => Ok(Curve::Linear),
+            LuaValue::String(name) => {
+                let name = name.to_str()?;
+                Curve::from_name(name)
+                    .ok_or_else(|| LuaError::RuntimeError(format!("unknown curve: {}", name)))
+            },
+            other => Err(LuaError::FromLuaConversionError {
+                from: other.type_name(),
+                to: "Curve",
+                message: Some("expected \"linear\", \"exp\" or \"step\"".to_string()),
+            }),
+        }
+    }
+}
+
+/// The tempo reached `beat` beats after a lane starts, and how it gets
+/// there from the previous point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TempoPoint {
+    pub beat: Time,
+    pub tempo: f64,
+    pub curve: Curve,
+}
+
+impl<'lua> FromLua<'lua> for TempoPoint {
+    /// `{beat, tempo, curve}`, the curve being optional.
+    fn from_lua(value: LuaValue<'lua>, lua: &'lua Lua) -> LuaResult<Self> {
+        let table = LuaTable::from_lua(value, lua)?;
+        let beat: f64 = table.get(1)?;
+        let tempo: f64 = table.get(2)?;
+        if beat < 0.0 || tempo <= 0.0 {
+            return Err(LuaError::RuntimeError(format!("invalid tempo point: {} bpm at beat {}", tempo, beat)));
+        }
+        Ok(TempoPoint { beat: to_time(beat), tempo, curve: table.get(3)? })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Segment {
+    begin: Time,
+    end: Time,
+    from: f64,
+    to: f64,
+    curve: Curve,
+}
+
+/// A tempo lane placed on the timeline. Curves are followed in steps of
+/// `RESOLUTION`, each applied by the clock once it is reached, so that the
+/// tempo to come is known exactly when scheduling ahead.
+#[derive(Debug, Clone)]
+pub struct Automation {
+    segments: Vec<Segment>,
+    /// The next change to apply, if any is left.
+    next: Option<Time>,
+}
+
+impl Automation {
+    /// `points` from `start` on, from `tempo`.
+    pub fn new(points: &[TempoPoint], start: Time, tempo: f64) -> Self {
+        let mut points = points.to_vec();
+        points.sort_by_key(|point| point.beat);
+        let (mut begin, mut from) = (start, tempo);
+        let mut segments = Vec::new();
+        for point in points {
+            let end = start + point.beat;
+            segments.push(Segment { begin, end, from, to: point.tempo, curve: point.curve });
+            (begin, from) = (end, point.tempo);
+        }
+        let next = (!segments.is_empty()).then_some(start);
+        Self { segments, next }
+    }
+
+    /// Keep following this automation until `start`, then `points` from
+    /// there on. Changes up to `start` may already be scheduled against.
+    pub fn then(mut self, points: &[TempoPoint], start: Time) -> Self {
+        let tempo = self.tempo_at(start);
+        self.segments.retain(|segment| segment.begin < start);
+        if let Some(last) = self.segments.last_mut() {
+            if last.end > start {
+                // Cut where it is at `start`, the curve staying the same.
+                (last.end, last.to) = (start, tempo);
+            }
+        }
+        self.segments.extend(Automation::new(points, start, tempo).segments);
+        self
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.next.is_none()
+    }
+
+    /// The change not applied yet.
+    pub fn next(&self) -> Option<Time> {
+        self.next
+    }
+
+    /// The change at `next` was applied.
+    pub fn advance(&mut self) {
+        self.next = self.next.and_then(|next| self.change_after(next));
+    }
+
+    /// The tempo from `beat` until the next change, within the range Link
+    /// accepts.
+    pub fn tempo_at(&self, beat: Time) -> f64 {
+        let segment = self.segments.iter().find(|segment| beat < segment.end);
+        let tempo = match segment {
+            Some(segment) if beat < segment.begin => segment.from,
+            Some(segment) => {
+                let x = to_f64((beat - segment.begin) / (segment.end - segment.begin));
+                segment.curve.tempo(segment.from, segment.to, x)
+            },
+            None => self.segments.last().map_or(120.0, |segment| segment.to),
+        };
+        tempo.clamp(20.0, 999.0)
+    }
+
+    /// The first change after `beat`: the next step of the resolution, or
+    /// the end of a segment.
+    fn change_after(&self, beat: Time) -> Option<Time> {
+        let start = self.segments.first()?.begin;
+        let end = self.segments.last()?.end;
+        if beat >= end {
+            return None;
+        }
+        let resolution = Time::new(RESOLUTION.0, RESOLUTION.1);
+        let step = start + ((beat - start) / resolution).floor() * resolution + resolution;
+        let segment = self.segments.iter().map(|segment| segment.end).find(|end| *end > beat);
+        Some(step.min(segment.unwrap_or(end)))
+    }
+}
+
+/// Beats to host time along the timeline of a source, as it will be once
+/// the changes of the automation still to come are applied.
+pub struct TempoMap<'a> {
+    source: &'a dyn TimeSource,
+    automation: Option<&'a Automation>,
+    quantum: f64,
+}
+
+impl<'a> TempoMap<'a> {
+    pub fn new(source: &'a dyn TimeSource, automation: Option<&'a Automation>, quantum: f64) -> Self {
+        Self { source, automation, quantum }
+    }
+
+    pub fn tempo(&self) -> f64 {
+        self.source.tempo()
+    }
+
+    /// The changes to come, with the tempo each one sets. The source is
+    /// followed until the first of them.
+    fn changes(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
+        let automation = self.automation;
+        let mut next = automation.and_then(Automation::next);
+        std::iter::from_fn(move || {
+            let (automation, at) = (automation?, next?);
+            next = automation.change_after(at);
+            Some((to_f64(at), automation.tempo_at(at)))
+        })
+    }
+
+    /// The tempo from `beat` until the next change.
+    pub fn tempo_at_beat(&self, beat: f64) -> f64 {
+        self.changes()
+            .take_while(|(at, _)| *at <= beat)
+            .last()
+            .map_or_else(|| self.tempo(), |(_, tempo)| tempo)
+    }
+
+    pub fn time_at_beat(&self, beat: f64) -> i64 {
+        let mut changes = self.changes().peekable();
+        match changes.peek() {
+            Some((at, _)) if beat > *at => {},
+            _ => return self.source.time_at_beat(beat, self.quantum),
+        }
+        let (mut from, mut tempo) = changes.next().unwrap_or_default();
+        let mut time = self.source.time_at_beat(from, self.quantum) as f64;
+        for (at, next_tempo) in changes {
+            if at >= beat {
+                break;
+            }
+            time += (at - from) * 60_000_000.0 / tempo;
+            (from, tempo) = (at, next_tempo);
+        }
+        (time + (beat - from) * 60_000_000.0 / tempo).round() as i64
+    }
+
+    pub fn beat_at_time(&self, time: i64) -> f64 {
+        let mut changes = self.changes();
+        let (mut from, mut tempo) = match changes.next() {
+            Some((at, tempo)) if time > self.source.time_at_beat(at, self.quantum) => (at, tempo),
+            _ => return self.source.beat_at_time(time, self.quantum),
+        };
+        let mut micros = self.source.time_at_beat(from, self.quantum) as f64;
+        for (at, next_tempo) in changes {
+            let at_micros = micros + (at - from) * 60_000_000.0 / tempo;
+            if at_micros >= time as f64 {
+                break;
+            }
+            (from, tempo, micros) = (at, next_tempo, at_micros);
+        }
+        from + (time as f64 - micros) / 60_000_000.0 * tempo
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time_source::{InternalSource, VirtualTime};
+
+    fn t(numer: i128, denom: i128) -> Time {
+        Time::new(numer, denom)
+    }
+
+    fn point(beat: Time, tempo: f64, curve: Curve) -> TempoPoint {
+        TempoPoint { beat, tempo, curve }
+    }
+
+    #[test]
+    fn tempo_along_curves() {
+        let linear = Automation::new(&[point(t(4, 1), 180.0, Curve::Linear)], t(8, 1), 120.0);
+        assert_eq!(linear.tempo_at(t(0, 1)), 120.0);
+        assert_eq!(linear.tempo_at(t(10, 1)), 150.0);
+        assert_eq!(linear.tempo_at(t(12, 1)), 180.0);
+        assert_eq!(linear.tempo_at(t(100, 1)), 180.0);
+        let exponential = Automation::new(&[point(t(4, 1), 200.0, Curve::Exponential)], t(0, 1), 100.0);
+        assert!((exponential.tempo_at(t(2, 1)) - 100.0 * 2f64.sqrt()).abs() < 1e-9);
+        let step = Automation::new(&[point(t(2, 1), 60.0, Curve::Step)], t(0, 1), 120.0);
+        assert_eq!(step.tempo_at(t(31, 16)), 120.0);
+        assert_eq!(step.tempo_at(t(2, 1)), 60.0);
+    }
+
+    #[test]
+    fn tempo_within_what_link_accepts() {
+        let lane = [point(t(1, 1), 5000.0, Curve::Linear), point(t(2, 1), 1.0, Curve::Step)];
+        let automation = Automation::new(&lane, t(0, 1), 120.0);
+        assert_eq!(automation.tempo_at(t(1, 1)), 999.0);
+        assert_eq!(automation.tempo_at(t(2, 1)), 20.0);
+    }
+
+    #[test]
+    fn changes_every_sixteenth_and_at_points() {
+        let lane = [point(t(1, 3), 130.0, Curve::Linear), point(t(1, 1), 140.0, Curve::Linear)];
+        let mut automation = Automation::new(&lane, t(1, 2), 120.0);
+        assert_eq!(automation.change_after(t(1, 2)), Some(t(9, 16)));
+        // The first point is between two steps.
+        assert_eq!(automation.change_after(t(13, 16)), Some(t(5, 6)));
+        assert_eq!(automation.change_after(t(5, 6)), Some(t(7, 8)));
+        assert_eq!(automation.change_after(t(3, 2)), None);
+        let mut changes = Vec::new();
+        while let Some(next) = automation.next() {
+            changes.push(next);
+            automation.advance();
+        }
+        assert_eq!(changes.len(), 18);
+        assert_eq!(changes.last(), Some(&t(3, 2)));
+        assert!(automation.is_over());
+    }
+
+    #[test]
+    fn beats_and_time_at_a_constant_tempo() {
+        let source = InternalSource::on_virtual_time(VirtualTime::default(), 120.0);
+        let map = TempoMap::new(&source, None, 4.0);
+        assert_eq!(map.time_at_beat(3.0), 1_500_000);
+        assert_eq!(map.beat_at_time(250_000), 0.5);
+        assert_eq!(map.tempo_at_beat(3.0), 120.0);
+    }
+
+    #[test]
+    fn beats_and_time_across_changes() {
+        let source = InternalSource::on_virtual_time(VirtualTime::default(), 120.0);
+        let automation = Automation::new(&[point(t(2, 1), 60.0, Curve::Step)], t(0, 1), 120.0);
+        let map = TempoMap::new(&source, Some(&automation), 4.0);
+        assert_eq!(map.tempo_at_beat(1.0), 120.0);
+        assert_eq!(map.tempo_at_beat(3.0), 60.0);
+        assert_eq!(map.time_at_beat(1.0), 500_000);
+        assert_eq!(map.time_at_beat(4.0), 3_000_000);
+        assert_eq!(map.beat_at_time(1_000_000), 2.0);
+        assert_eq!(map.beat_at_time(2_000_000), 3.0);
+    }
+
+    #[test]
+    fn beats_and_time_agree_during_ramps() {
+        let source = InternalSource::on_virtual_time(VirtualTime::default(), 90.0);
+        let automation = Automation::new(&[point(t(8, 1), 170.0, Curve::Exponential)], t(1, 1), 90.0);
+        let map = TempoMap::new(&source, Some(&automation), 4.0);
+        let mut previous = 0;
+        for beat in [0.5, 1.0, 1.03, 2.5, 4.2, 8.999, 9.0, 12.0] {
+            let time = map.time_at_beat(beat);
+            assert!(time > previous);
+            assert!((map.beat_at_time(time) - beat).abs() < 1e-5, "beat {}", beat);
+            previous = time;
+        }
+        // Past the ramp, a beat lasts as long as at its final tempo.
+        let beat = map.time_at_beat(12.0) - map.time_at_beat(11.0);
+        assert!((beat as f64 - 60_000_000.0 / 170.0).abs() <= 1.0);
+    }
+}
@@ -0,0 +1,291 @@
+use rusty_link::{AblLink, SessionState};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// Where the clock gets its timeline from. Times are host times in
+/// microseconds, given by `clock_micros`, the same for every source so that
+/// the dispatcher keeps its deadlines when the source changes.
+pub trait TimeSource: Send {
+    fn kind(&self) -> SourceKind;
+    fn clock_micros(&self) -> i64;
+    /// Refresh the local copy of the timeline, before reading it.
+    fn capture(&mut self) {}
+    /// Share the local changes with peers, if any.
+    fn commit(&mut self) {}
+    fn tempo(&self) -> f64;
+    /// Change the tempo from host time `at` on.
+    fn set_tempo(&mut self, tempo: f64, at: i64);
+    fn beat_at_time(&self, time: i64, quantum: f64) -> f64;
+    fn phase_at_time(&self, time: i64, quantum: f64) -> f64 {
+        self.beat_at_time(time, quantum).rem_euclid(quantum)
+    }
+    fn time_at_beat(&self, beat: f64, quantum: f64) -> i64;
+    fn is_playing(&self) -> bool;
+    fn stop(&mut self, at: i64);
+    /// Start playing at host time `at`, from `beat`.
+    fn start(&mut self, at: i64, beat: f64, quantum: f64);
+    fn num_peers(&self) -> u64 {
+        0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceKind {
+    /// The Link session on the local network.
+    Link,
+    /// A clock of our own, joining nobody.
+    Internal,
+    /// The internal clock on virtual time, used when rendering.
+    Virtual,
+}
+
+impl SourceKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "link" => Some(SourceKind::Link),
+            "internal" => Some(SourceKind::Internal),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SourceKind::Link => "link",
+            SourceKind::Internal => "internal",
+            SourceKind::Virtual => "virtual",
+        }
+    }
+}
+
+/// The Link session. Link is enabled for as long as the source is used.
+pub struct LinkSource {
+    link: Arc<AblLink>,
+    session_state: SessionState,
+}
+
+impl LinkSource {
+    pub fn new(link: Arc<AblLink>) -> Self {
+        link.enable_start_stop_sync(true);
+        link.enable(true);
+        let mut session_state = SessionState::new();
+        link.capture_app_session_state(&mut session_state);
+        Self { link, session_state }
+    }
+}
+
+impl Drop for LinkSource {
+    fn drop(&mut self) {
+        self.link.enable(false);
+    }
+}
+
+impl TimeSource for LinkSource {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Link
+    }
+
+    fn clock_micros(&self) -> i64 {
+        self.link.clock_micros()
+    }
+
+    fn capture(&mut self) {
+        self.link.capture_app_session_state(&mut self.session_state);
+    }
+
+    fn commit(&mut self) {
+        self.link.commit_app_session_state(&self.session_state);
+    }
+
+    fn tempo(&self) -> f64 {
+        self.session_state.tempo()
+    }
+
+    fn set_tempo(&mut self, tempo: f64, at: i64) {
+        self.session_state.set_tempo(tempo, at);
+    }
+
+    fn beat_at_time(&self, time: i64, quantum: f64) -> f64 {
+        self.session_state.beat_at_time(time, quantum)
+    }
+
+    fn phase_at_time(&self, time: i64, quantum: f64) -> f64 {
+        self.session_state.phase_at_time(time, quantum)
+    }
+
+    fn time_at_beat(&self, beat: f64, quantum: f64) -> i64 {
+        self.session_state.time_at_beat(beat, quantum)
+    }
+
+    fn is_playing(&self) -> bool {
+        self.session_state.is_playing()
+    }
+
+    fn stop(&mut self, at: i64) {
+        self.session_state.set_is_playing(false, at as u64);
+    }
+
+    fn start(&mut self, at: i64, beat: f64, quantum: f64) {
+        self.session_state.set_is_playing_and_request_beat_at_time(true, at as u64, beat, quantum);
+    }
+
+    fn num_peers(&self) -> u64 {
+        self.link.num_peers()
+    }
+}
+
+/// A tempo and the beat at some host time, moved whenever the tempo
+/// changes.
+#[derive(Debug, Clone, Copy)]
+struct Timeline {
+    tempo: f64,
+    /// The beat at `micros`.
+    beat: f64,
+    micros: i64,
+    playing: bool,
+}
+
+impl Timeline {
+    fn new(tempo: f64, micros: i64) -> Self {
+        Self { tempo, beat: 0.0, micros, playing: false }
+    }
+
+    /// The timeline of `source`, as it stands at `micros`.
+    fn of(source: &dyn TimeSource, micros: i64, quantum: f64) -> Self {
+        Self {
+            tempo: source.tempo(),
+            beat: source.beat_at_time(micros, quantum),
+            micros,
+            playing: source.is_playing(),
+        }
+    }
+
+    fn set_tempo(&mut self, tempo: f64, at: i64) {
+        self.beat = self.beat_at_time(at);
+        self.micros = at;
+        self.tempo = tempo.clamp(20.0, 999.0);
+    }
+
+    fn beat_at_time(&self, time: i64) -> f64 {
+        self.beat + (time - self.micros) as f64 / 60_000_000.0 * self.tempo
+    }
+
+    fn time_at_beat(&self, beat: f64) -> i64 {
+        self.micros + ((beat - self.beat) * 60_000_000.0 / self.tempo).round() as i64
+    }
+
+    fn start(&mut self, at: i64, beat: f64) {
+        self.beat = beat;
+        self.micros = at;
+        self.playing = true;
+    }
+}
+
+/// Host time that only moves when told to, shared between a virtual clock
+/// and whoever drives it.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualTime(Arc<AtomicI64>);
+
+impl VirtualTime {
+    pub fn now(&self) -> i64 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub fn advance(&self, micros: i64) {
+        self.0.fetch_add(micros, Ordering::AcqRel);
+    }
+}
+
+/// Where the internal clock reads the time.
+#[derive(Clone)]
+pub enum Host {
+    /// The host clock of Link, which is the monotonic clock of the system.
+    /// Link itself stays disabled.
+    System(Arc<AblLink>),
+    Virtual(VirtualTime),
+}
+
+impl Host {
+    /// The time, in microseconds.
+    pub fn now(&self) -> i64 {
+        match self {
+            Host::System(link) => link.clock_micros(),
+            Host::Virtual(time) => time.now(),
+        }
+    }
+}
+
+/// A timeline of our own, joining nobody.
+pub struct InternalSource {
+    host: Host,
+    timeline: Timeline,
+}
+
+impl InternalSource {
+    pub fn new(link: Arc<AblLink>, tempo: f64) -> Self {
+        let timeline = Timeline::new(tempo, link.clock_micros());
+        Self { host: Host::System(link), timeline }
+    }
+
+    /// Carry on from the timeline of another source.
+    pub fn follow(link: Arc<AblLink>, source: &dyn TimeSource, quantum: f64) -> Self {
+        let timeline = Timeline::of(source, link.clock_micros(), quantum);
+        Self { host: Host::System(link), timeline }
+    }
+
+    /// A clock for rendering and tests: nothing happens until `time` is
+    /// advanced, however fast that is.
+    pub fn on_virtual_time(time: VirtualTime, tempo: f64) -> Self {
+        let timeline = Timeline::new(tempo, time.now());
+        Self { host: Host::Virtual(time), timeline }
+    }
+}
+
+impl TimeSource for InternalSource {
+    fn kind(&self) -> SourceKind {
+        match self.host {
+            Host::System(_) => SourceKind::Internal,
+            Host::Virtual(_) => SourceKind::Virtual,
+        }
+    }
+
+    fn clock_micros(&self) -> i64 {
+        self.host.now()
+    }
+
+    fn tempo(&self) -> f64 {
+        self.timeline.tempo
+    }
+
+    fn set_tempo(&mut self, tempo: f64, at: i64) {
+        self.timeline.set_tempo(tempo, at);
+    }
+
+    fn beat_at_time(&self, time: i64, _quantum: f64) -> f64 {
+        self.timeline.beat_at_time(time)
+    }
+
+    fn time_at_beat(&self, beat: f64, _quantum: f64) -> i64 {
+        self.timeline.time_at_beat(beat)
+    }
+
+    fn is_playing(&self) -> bool {
+        self.timeline.playing
+    }
+
+    fn stop(&mut self, _at: i64) {
+        self.timeline.playing = false;
+    }
+
+    fn start(&mut self, at: i64, beat: f64, _quantum: f64) {
+        self.timeline.start(at, beat);
+    }
+}
+
+/// The source `kind`, at `tempo` when it has no timeline to join.
+pub fn create(kind: SourceKind, link: Arc<AblLink>, tempo: f64) -> Box<dyn TimeSource> {
+    match kind {
+        SourceKind::Link => Box::new(LinkSource::new(link)),
+        SourceKind::Internal => Box::new(InternalSource::new(link, tempo)),
+        SourceKind::Virtual => Box::new(InternalSource::on_virtual_time(VirtualTime::default(), tempo)),
+    }
+}
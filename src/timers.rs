@@ -0,0 +1,313 @@
+use mlua::prelude::*;
+use num::Zero;
+use std::sync::mpsc::Sender;
+
+use crate::clock::ClockCommand;
+use crate::interpreter::EvalQueue;
+use crate::routines;
+use crate::streams::{StreamContent, Time, to_f64, to_time};
+
+/// Registry table holding the Lua functions of the timers, by id.
+const TIMERS: &str = "eremit.timers";
+/// Registry table holding the stream generators, by stream name: a table
+/// with the function under `fn` and its state under `state`.
+const GENERATORS: &str = "eremit.generators";
+
+/// How often a timer fires.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Bar,
+    Beats(Time),
+}
+
+impl Period {
+    pub fn beats(&self, quantum: f64) -> Time {
+        match self {
+            Period::Bar => to_time(quantum),
+            Period::Beats(beats) => *beats,
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for Period {
+    /// A number of beats, possibly a fraction given as a string (`"1/3"`),
+    /// `"beat"` or `"bar"`.
+    fn from_lua(value: LuaValue<'lua>, _lua: &'lua Lua) -> LuaResult<Self> {
+        let beats = match value {
+            LuaValue::Integer(beats) => Time::from_integer(beats.into()),
+            LuaValue::Number(beats) => to_time(beats),
+            LuaValue::String(ref text) => match text.to_str()? {
+                "bar" => return Ok(Period::Bar),
+                "beat" => Time::from_integer(1),
+                text => parse_fraction(text).ok_or_else(|| {
+                    LuaError::RuntimeError(format!("invalid period: {}", text))
+                })?,
+            },
+            other => return Err(LuaError::FromLuaConversionError {
+                from: other.type_name(),
+                to: "Period",
+                message: Some("expected a number of beats, \"beat\" or \"bar\"".to_string()),
+            }),
+        };
+        match beats > Time::zero() {
+            true => Ok(Period::Beats(beats)),
+            false => Err(LuaError::RuntimeError("the period must be positive".to_string())),
+        }
+    }
+}
+
+/// `"3"` or `"1/3"`.
+fn parse_fraction(text: &str) -> Option<Time> {
+    match text.split_once('/') {
+        Some((numerator, denominator)) => {
+            let numerator: i128 = numerator.trim().parse().ok()?;
+            let denominator: i128 = denominator.trim().parse().ok()?;
+            (denominator != 0).then(|| Time::new(numerator, denominator))
+        },
+        None => Some(Time::from_integer(text.trim().parse().ok()?)),
+    }
+}
+
+/// What a routine yielded for: a duration, or the next multiple of a period.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wait {
+    For(Period),
+    Sync(Period),
+}
+
+impl Wait {
+    /// The beat at which a routine waiting from `beat` wakes up.
+    pub fn until(&self, beat: Time, quantum: f64) -> Time {
+        match self {
+            Wait::For(period) => beat + period.beats(quantum),
+            Wait::Sync(period) => {
+                let period = period.beats(quantum);
+                (beat / period).floor() * period + period
+            },
+        }
+    }
+}
+
+/// What a callback returned: content played once from the boundary it
+/// fired for, and a tempo taking effect at that boundary.
+#[derive(Debug, Clone, Default)]
+pub struct Fired {
+    pub content: Option<StreamContent>,
+    pub tempo: Option<f64>,
+}
+
+impl<'lua> FromLua<'lua> for Fired {
+    /// `nil`, a pattern or a table of events, or a table with `events` and
+    /// `tempo` fields. Other values, such as the last expression of a
+    /// callback computing a number, are ignored: the tempo is shared with
+    /// Link peers, so it is only changed when asked for by name.
+    fn from_lua(value: LuaValue<'lua>, lua: &'lua Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Nil | LuaValue::Integer(_) | LuaValue::Number(_) | LuaValue::Boolean(_) => {
+                Ok(Fired::default())
+            },
+            LuaValue::Table(table) if table.contains_key("events")? || table.contains_key("tempo")? => {
+                Ok(Fired {
+                    content: table.get("events")?,
+                    tempo: table.get("tempo")?,
+                })
+            },
+            value => Ok(Fired {
+                content: Some(StreamContent::from_lua(value, lua)?),
+                tempo: None,
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Timer {
+    id: u64,
+    period: Period,
+}
+
+/// Lua callbacks fired on rational subdivisions of the Link timeline. The
+/// clock thread hands them to the Lua thread ahead of time, and what they
+/// return comes back to the clock to be played at the exact boundary.
+/// Streams generated by a function are handled the same way, once per cycle.
+pub struct Timers {
+    timers: Vec<Timer>,
+    /// Streams whose content is generated every cycle.
+    generators: Vec<String>,
+    /// Routines waiting to be resumed, with the beat they wait for.
+    routines: Vec<(u64, Time)>,
+    /// The beat up to which timers have already fired.
+    horizon: Option<Time>,
+    /// How far ahead of the scheduler window timers fire, in milliseconds.
+    lead: f64,
+    queue: EvalQueue,
+    clock: Sender<ClockCommand>,
+}
+
+impl Timers {
+    pub fn new(queue: EvalQueue, clock: Sender<ClockCommand>, lead: f64) -> Self {
+        Self {
+            timers: Vec::new(),
+            generators: Vec::new(),
+            routines: Vec::new(),
+            horizon: None,
+            lead,
+            queue,
+            clock,
+        }
+    }
+
+    /// The lead in beats at `tempo`.
+    pub fn lead(&self, tempo: f64) -> Time {
+        to_time(self.lead / 1000.0 * tempo / 60.0)
+    }
+
+    pub fn add(&mut self, id: u64, period: Period) {
+        self.timers.push(Timer { id, period });
+    }
+
+    /// Remove timer or routine `id`.
+    pub fn remove(&mut self, id: u64) {
+        self.timers.retain(|timer| timer.id != id);
+        self.routines.retain(|(routine, _)| *routine != id);
+    }
+
+    /// Resume routine `id` once the clock reaches `beat`.
+    pub fn wake_at(&mut self, id: u64, beat: Time) {
+        self.routines.push((id, beat));
+    }
+
+    pub fn add_generator(&mut self, name: String) {
+        if !self.generates(&name) {
+            self.generators.push(name);
+        }
+    }
+
+    pub fn remove_generator(&mut self, name: &str) {
+        self.generators.retain(|generator| generator != name);
+    }
+
+    pub fn generates(&self, name: &str) -> bool {
+        self.generators.iter().any(|generator| generator == name)
+    }
+
+    /// Start over from the current beat, as after the transport stopped.
+    /// Routines are dropped, their beats meaning nothing anymore.
+    pub fn reset(&mut self) {
+        self.horizon = None;
+        self.routines.clear();
+    }
+
+    /// Fire every timer whose boundary falls between the previous call and
+    /// `until`, starting from `beat` the first time, and ask generators for
+    /// the cycles starting in that window. Routines due by `until` are
+    /// resumed, each at the beat it waited for, until they wait past it.
+    pub fn fire(&mut self, beat: Time, until: Time, quantum: f64, tempo: f64) {
+        let from = self.horizon.unwrap_or(beat);
+        if until <= from {
+            return;
+        }
+        self.horizon = Some(until);
+        let (due, waiting) = self.routines.drain(..).partition(|(_, at)| *at < until);
+        self.routines = waiting;
+        for (id, at) in due {
+            let clock = self.clock.clone();
+            let _ = self.queue.call(Box::new(move |lua: &Lua| routines::resume(lua, id, at, until, quantum, clock)));
+        }
+        for timer in &self.timers {
+            let period = timer.period.beats(quantum);
+            if period <= Time::zero() {
+                continue;
+            }
+            let mut boundary = (from / period).ceil() * period;
+            while boundary < until {
+                let (id, clock) = (timer.id, self.clock.clone());
+                let _ = self.queue.call(Box::new(move |lua: &Lua| call(lua, id, boundary, period, clock)));
+                boundary += period;
+            }
+        }
+        let bar = to_time(quantum);
+        let mut boundary = (from / bar).ceil() * bar;
+        while boundary < until {
+            let cycle = (boundary / bar).to_integer() as i64;
+            for name in &self.generators {
+                let (name, clock) = (name.clone(), self.clock.clone());
+                let _ = self.queue.call(Box::new(move |lua: &Lua| generate(lua, name, cycle, tempo, clock)));
+            }
+            boundary += bar;
+        }
+    }
+}
+
+/// The registry table `name`, created on first use.
+pub fn registry_table<'lua>(lua: &'lua Lua, name: &str) -> LuaResult<LuaTable<'lua>> {
+    match lua.named_registry_value::<Option<LuaTable>>(name)? {
+        Some(table) => Ok(table),
+        None => {
+            let table = lua.create_table()?;
+            lua.set_named_registry_value(name, table.clone())?;
+            Ok(table)
+        }
+    }
+}
+
+fn callbacks(lua: &Lua) -> LuaResult<LuaTable<'_>> {
+    registry_table(lua, TIMERS)
+}
+
+/// Keep the function of timer `id`, on the Lua thread.
+pub fn register(lua: &Lua, id: u64, function: LuaFunction) -> LuaResult<()> {
+    callbacks(lua)?.set(id, function)
+}
+
+pub fn unregister(lua: &Lua, id: u64) -> LuaResult<()> {
+    callbacks(lua)?.set(id, LuaValue::Nil)
+}
+
+/// Call timer `id` for `boundary` and send what it returns to the clock.
+fn call(lua: &Lua, id: u64, boundary: Time, length: Time, clock: Sender<ClockCommand>) -> LuaResult<()> {
+    // Cancelled since the clock fired it.
+    let Some(function) = callbacks(lua)?.get::<_, Option<LuaFunction>>(id)? else {
+        return Ok(());
+    };
+    let fired: Fired = function.call(to_f64(boundary))?;
+    if fired.content.is_some() || fired.tempo.is_some() {
+        let _ = clock.send(ClockCommand::Fired(boundary, Some(length), fired));
+    }
+    Ok(())
+}
+
+/// Generate stream `name` with `function`. A stream generated before keeps
+/// its state, so that redefining the function does not start over.
+pub fn register_generator(lua: &Lua, name: &str, function: LuaFunction) -> LuaResult<()> {
+    let generators = registry_table(lua, GENERATORS)?;
+    let generator = match generators.get::<_, Option<LuaTable>>(name)? {
+        Some(generator) => generator,
+        None => {
+            let generator = lua.create_table()?;
+            generator.set("state", lua.create_table()?)?;
+            generators.set(name, generator.clone())?;
+            generator
+        }
+    };
+    generator.set("fn", function)
+}
+
+/// Ask the generator of stream `name` for `cycle` and send the content to
+/// the clock. Errors are reported and the stream keeps playing the last
+/// cycle it got.
+fn generate(lua: &Lua, name: String, cycle: i64, tempo: f64, clock: Sender<ClockCommand>) -> LuaResult<()> {
+    let Some(generator) = registry_table(lua, GENERATORS)?.get::<_, Option<LuaTable>>(name.as_str())? else {
+        return Ok(());
+    };
+    let function: LuaFunction = generator.get("fn")?;
+    let state: LuaTable = generator.get("state")?;
+    match function.call::<_, Option<StreamContent>>((cycle, tempo, state)) {
+        Ok(content) => {
+            let content = content.unwrap_or(StreamContent::Events(Vec::new()));
+            let _ = clock.send(ClockCommand::Generated(name, cycle, content));
+        },
+        Err(err) => eprintln!("error: stream {}, cycle {}: {}", name, cycle, err),
+    }
+    Ok(())
+}